@@ -0,0 +1,234 @@
+//! The architecture specific jump into the SHELF
+//!
+//! Each `jump` copies `len` words from `vector` up from `sp`, installs the thread pointer if it
+//! isn't null, points the stack pointer at `sp` and branches to `entry`. Everything happens in
+//! asm as the copy may overwrite our own stack frames and the loader's own TLS is unusable once
+//! the thread pointer changes.
+//!
+//! Before the branch each of the `init_len` constructors at `init` is called with argc, argv and
+//! envp from the copied vector. They run from there so they see the SHELF's stack and TLS.
+//!
+//! The general purpose registers are zeroed before the branch so the SHELF doesn't start out
+//! holding loader addresses. That includes the register the ABI uses to pass an atexit function
+//! (rdx, x0 or edx), so the SHELF sees there isn't one. The branch itself goes through a return
+//! address (pushed just below `sp` on x86, x30 on aarch64) so no register has to keep `entry`.
+
+use core::arch::asm;
+use libc::c_void;
+
+#[cfg(target_arch = "x86_64")]
+pub(crate) unsafe fn jump(
+    entry: *const c_void,
+    sp: *mut usize,
+    vector: *const usize,
+    len: usize,
+    thread_pointer: *mut c_void,
+    init: *const *const c_void,
+    init_len: usize,
+) -> ! {
+    // The operands are pinned to registers the syscall leaves alone (it clobbers rcx and r11).
+    // The ones still needed after the constructors are also callee saved.
+    asm!(
+        "test r8, r8",
+        "jz 2f",
+        "mov eax, 158",    // SYS_arch_prctl
+        "mov edi, 0x1002", // ARCH_SET_FS
+        "mov rsi, r8",
+        "syscall",
+        "2:",
+        "mov rdi, r9",
+        "mov rsi, r10",
+        "mov rcx, r12",
+        "rep movsq",
+        "mov rsp, r9",
+        "3:",
+        "test r15, r15",
+        "jz 4f",
+        "mov rdi, [rsp]",
+        "lea rsi, [rsp + 8]",
+        "lea rdx, [rsi + rdi * 8 + 8]",
+        "call [r14]",
+        "add r14, 8",
+        "dec r15",
+        "jmp 3b",
+        "4:",
+        "push r13",
+        "xor eax, eax",
+        "xor ebx, ebx",
+        "xor ecx, ecx",
+        "xor edx, edx",
+        "xor esi, esi",
+        "xor edi, edi",
+        "xor ebp, ebp",
+        "xor r8d, r8d",
+        "xor r9d, r9d",
+        "xor r10d, r10d",
+        "xor r11d, r11d",
+        "xor r12d, r12d",
+        "xor r13d, r13d",
+        "xor r14d, r14d",
+        "xor r15d, r15d",
+        "ret",
+        in("r8") thread_pointer,
+        in("r9") sp,
+        in("r10") vector,
+        in("r12") len,
+        in("r13") entry,
+        in("r14") init,
+        in("r15") init_len,
+        options(noreturn),
+    );
+}
+
+#[cfg(target_arch = "aarch64")]
+pub(crate) unsafe fn jump(
+    entry: *const c_void,
+    sp: *mut usize,
+    vector: *const usize,
+    len: usize,
+    thread_pointer: *mut c_void,
+    init: *const *const c_void,
+    init_len: usize,
+) -> ! {
+    // x5 and x6 are scratch, the operands are pinned so they can't be handed out. The ones still
+    // needed after the constructors are callee saved.
+    asm!(
+        "cbz x0, 2f",
+        "msr tpidr_el0, x0",
+        "2:",
+        "mov x5, x1",
+        "3:",
+        "cbz x3, 4f",
+        "ldr x6, [x2], #8",
+        "str x6, [x5], #8",
+        "sub x3, x3, #1",
+        "b 3b",
+        "4:",
+        "mov sp, x1",
+        "5:",
+        "cbz x21, 6f",
+        "ldr x0, [sp]",
+        "add x1, sp, #8",
+        "add x2, x1, x0, lsl #3",
+        "add x2, x2, #8",
+        "ldr x5, [x20], #8",
+        "blr x5",
+        "sub x21, x21, #1",
+        "b 5b",
+        "6:",
+        "mov x30, x22",
+        "mov x0, xzr",
+        "mov x1, xzr",
+        "mov x2, xzr",
+        "mov x3, xzr",
+        "mov x4, xzr",
+        "mov x5, xzr",
+        "mov x6, xzr",
+        "mov x7, xzr",
+        "mov x8, xzr",
+        "mov x9, xzr",
+        "mov x10, xzr",
+        "mov x11, xzr",
+        "mov x12, xzr",
+        "mov x13, xzr",
+        "mov x14, xzr",
+        "mov x15, xzr",
+        "mov x16, xzr",
+        "mov x17, xzr",
+        "mov x18, xzr",
+        "mov x19, xzr",
+        "mov x20, xzr",
+        "mov x21, xzr",
+        "mov x22, xzr",
+        "mov x23, xzr",
+        "mov x24, xzr",
+        "mov x25, xzr",
+        "mov x26, xzr",
+        "mov x27, xzr",
+        "mov x28, xzr",
+        "mov x29, xzr",
+        "ret",
+        in("x0") thread_pointer,
+        in("x1") sp,
+        in("x2") vector,
+        in("x3") len,
+        in("x20") init,
+        in("x21") init_len,
+        in("x22") entry,
+        options(noreturn),
+    );
+}
+
+#[cfg(target_arch = "x86")]
+pub(crate) unsafe fn jump(
+    entry: *const c_void,
+    sp: *mut usize,
+    vector: *const usize,
+    len: usize,
+    thread_pointer: *mut c_void,
+    init: *const *const c_void,
+    init_len: usize,
+) -> ! {
+    // i386 TLS goes through a GDT entry loaded into %gs. Allocating the entry doesn't touch our
+    // own %gs so it can happen out here. Without one the SHELF runs without TLS.
+    let selector = if thread_pointer.is_null() {
+        0
+    } else {
+        crate::tls::set_thread_area(thread_pointer).unwrap_or(0)
+    };
+    // LLVM reserves esi and ebx so the operands are passed through memory instead. They're all in
+    // registers before the copy can overwrite them.
+    let args = [
+        selector as usize,
+        sp as usize,
+        vector as usize,
+        len,
+        entry as usize,
+        init as usize,
+        init_len,
+    ];
+    asm!(
+        "mov edi, [eax + 4]",
+        "mov esi, [eax + 8]",
+        "mov ecx, [eax + 12]",
+        "mov edx, [eax + 16]",
+        "mov ebx, [eax + 20]",
+        "mov ebp, [eax + 24]",
+        "mov eax, [eax]",
+        "test eax, eax",
+        "jz 2f",
+        "mov gs, ax",
+        "2:",
+        "mov esp, edi",
+        "rep movsd",
+        // Constructors take their arguments on the stack, which stays 16 byte aligned for them
+        "mov esi, edx",
+        "3:",
+        "test ebp, ebp",
+        "jz 4f",
+        "mov eax, [esp]",
+        "lea ecx, [esp + 4]",
+        "lea edx, [ecx + eax * 4 + 4]",
+        "sub esp, 4",
+        "push edx",
+        "push ecx",
+        "push eax",
+        "call [ebx]",
+        "add esp, 16",
+        "add ebx, 4",
+        "dec ebp",
+        "jmp 3b",
+        "4:",
+        "push esi",
+        "xor eax, eax",
+        "xor ebx, ebx",
+        "xor ecx, ecx",
+        "xor edx, edx",
+        "xor esi, esi",
+        "xor edi, edi",
+        "xor ebp, ebp",
+        "ret",
+        in("eax") args.as_ptr(),
+        options(noreturn),
+    );
+}
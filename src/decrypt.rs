@@ -0,0 +1,42 @@
+//! Decrypting SHELFs that were encrypted before being handed to the loader
+
+use crate::{LoaderError, Result};
+
+/// How the SHELF handed to a [`Loader`](crate::Loader) is encrypted
+#[derive(Clone)]
+pub enum DecryptScheme {
+    /// XOR with the key repeated over the whole file. A one byte key XORs every byte with it.
+    Xor(Vec<u8>),
+    /// AES-256-GCM with the tag appended to the ciphertext, the way the aes-gcm crate produces it
+    #[cfg(feature = "aes")]
+    Aes256Gcm { key: [u8; 32], nonce: [u8; 12] },
+}
+
+impl DecryptScheme {
+    /// Decrypt the file into a new buffer. AES-GCM checks the tag so a wrong key or a tampered file
+    /// fails instead of producing garbage.
+    pub fn decrypt(&self, raw_file: &[u8]) -> Result<Vec<u8>> {
+        match self {
+            DecryptScheme::Xor(key) => {
+                if key.is_empty() {
+                    return Err(LoaderError::DecryptFailed);
+                }
+                Ok(raw_file
+                    .iter()
+                    .zip(key.iter().cycle())
+                    .map(|(byte, key)| byte ^ key)
+                    .collect())
+            }
+            #[cfg(feature = "aes")]
+            DecryptScheme::Aes256Gcm { key, nonce } => {
+                use aes_gcm::aead::{Aead, KeyInit};
+                use aes_gcm::{Aes256Gcm, Key, Nonce};
+
+                let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key));
+                cipher
+                    .decrypt(Nonce::from_slice(nonce), raw_file)
+                    .map_err(|_| LoaderError::DecryptFailed)
+            }
+        }
+    }
+}
@@ -0,0 +1,132 @@
+//! Errors that can happen while loading a SHELF
+
+use goblin::elf::header::{et_to_str, machine_to_str};
+use std::fmt;
+use std::io;
+
+#[derive(Debug)]
+pub enum LoaderError {
+    /// Reading the SHELF failed
+    Io(io::Error),
+    /// goblin couldn't parse the SHELF
+    Parse(goblin::error::Error),
+    /// The SHELF couldn't be decrypted, e.g. the AES-GCM tag didn't match
+    DecryptFailed,
+    /// The SHELF looked compressed but couldn't be decompressed
+    Decompress(io::Error),
+    /// The file parsed but isn't an ELF
+    UnsupportedFileType,
+    /// The ELF's class doesn't match the loader's pointer width
+    UnsupportedArch,
+    /// The ELF's byte order (EI_DATA) doesn't match the loader's
+    EndianMismatch,
+    /// The ELF is for a different machine than the one the loader runs on (e_machine values)
+    ArchMismatch { expected: u16, found: u16 },
+    /// The ELF isn't an executable or shared object, e.g. an object file (e_type value)
+    UnsupportedElfType(u16),
+    /// There are no PT_LOAD segments to map
+    NoLoadableSegment,
+    /// A header holds an offset, address or size that doesn't fit in the image
+    BadOffset,
+    /// A segment's file contents extend past the end of the file
+    TruncatedFile,
+    /// Two PT_LOAD segments cover the same addresses
+    OverlappingSegments,
+    /// The entry point isn't in an executable loadable segment (e_entry value)
+    OutOfBounds(u64),
+    /// A dynamic relocation of a type the loader doesn't handle
+    UnsupportedRelocation(u32),
+    /// A relocation refers to a symbol the SHELF doesn't define
+    UnresolvedSymbol(String),
+    /// An argument or environment variable contains a NUL byte
+    InvalidString(String),
+    /// The arguments and environment don't fit on the SHELF's stack
+    StackTooSmall,
+    /// A non-PIE ELF's fixed load address is already taken in this process (the page aligned start)
+    AddressInUse(usize),
+    /// mmap failed
+    Mmap(io::Error),
+    /// mprotect failed
+    Mprotect(io::Error),
+    /// getrandom failed
+    GetRandom(io::Error),
+    /// Loading failed in the child forked by `Loader::spawn`, with the error it reported
+    ChildFailed(String),
+}
+
+pub type Result<T> = std::result::Result<T, LoaderError>;
+
+impl fmt::Display for LoaderError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LoaderError::Io(err) => write!(f, "failed to read SHELF: {}", err),
+            LoaderError::Parse(err) => write!(f, "failed to parse SHELF: {}", err),
+            LoaderError::DecryptFailed => write!(f, "failed to decrypt SHELF"),
+            LoaderError::Decompress(err) => write!(f, "failed to decompress SHELF: {}", err),
+            LoaderError::UnsupportedFileType => write!(f, "filetype not supported"),
+            LoaderError::UnsupportedArch => write!(f, "ELF class doesn't match the loader's"),
+            LoaderError::EndianMismatch => write!(f, "ELF byte order doesn't match the loader's"),
+            LoaderError::ArchMismatch { expected, found } => write!(
+                f,
+                "SHELF is for {} but the loader runs on {}",
+                machine_to_str(*found),
+                machine_to_str(*expected)
+            ),
+            LoaderError::UnsupportedElfType(e_type) => write!(
+                f,
+                "ELF type {} isn't supported, the loader needs a linked executable or shared object",
+                et_to_str(*e_type)
+            ),
+            LoaderError::NoLoadableSegment => write!(f, "no loadable segments"),
+            LoaderError::BadOffset => write!(f, "header contains an out of range offset"),
+            LoaderError::TruncatedFile => write!(f, "segment extends past the end of the file"),
+            LoaderError::OverlappingSegments => write!(f, "loadable segments overlap"),
+            LoaderError::OutOfBounds(entry) => {
+                write!(f, "entry point {:#x} isn't in an executable segment", entry)
+            }
+            LoaderError::UnsupportedRelocation(r_type) => {
+                write!(f, "unsupported relocation type {}", r_type)
+            }
+            LoaderError::UnresolvedSymbol(name) => write!(f, "unresolved symbol {}", name),
+            LoaderError::InvalidString(s) => write!(f, "{:?} contains a NUL byte", s),
+            LoaderError::StackTooSmall => {
+                write!(f, "arguments and environment don't fit on the stack")
+            }
+            LoaderError::AddressInUse(addr) => write!(
+                f,
+                "SHELF must be mapped at {:#x} but something is already there",
+                addr
+            ),
+            LoaderError::Mmap(err) => write!(f, "mmap failed: {}", err),
+            LoaderError::Mprotect(err) => write!(f, "mprotect failed: {}", err),
+            LoaderError::GetRandom(err) => write!(f, "getrandom failed: {}", err),
+            LoaderError::ChildFailed(msg) => write!(f, "loading failed in the child: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for LoaderError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            LoaderError::Io(err)
+            | LoaderError::Decompress(err)
+            | LoaderError::Mmap(err)
+            | LoaderError::Mprotect(err)
+            | LoaderError::GetRandom(err) => Some(err),
+            LoaderError::Parse(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for LoaderError {
+    fn from(err: io::Error) -> Self {
+        LoaderError::Io(err)
+    }
+}
+
+impl From<goblin::error::Error> for LoaderError {
+    fn from(err: goblin::error::Error) -> Self {
+        LoaderError::Parse(err)
+    }
+}
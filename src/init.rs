@@ -0,0 +1,77 @@
+//! Constructors a SHELF expects to have run before its entry point, and its destructors
+
+use crate::reloc::slot;
+use crate::{dynamic_entry, to_usize, Result};
+use goblin::elf::dynamic::{
+    DT_FINI, DT_FINI_ARRAY, DT_FINI_ARRAYSZ, DT_INIT, DT_INIT_ARRAY, DT_INIT_ARRAYSZ,
+    DT_PREINIT_ARRAY, DT_PREINIT_ARRAYSZ,
+};
+use goblin::elf::Elf;
+use libc::c_void;
+use std::ops::Range;
+
+// Read the function pointer array at vaddr from the image.
+// The image has been relocated by now so the entries are already absolute addresses.
+fn read_array(
+    base: *mut c_void,
+    mapped: &[Range<usize>],
+    vaddr: Option<u64>,
+    size: Option<u64>,
+) -> Result<Vec<*const c_void>> {
+    let (Some(vaddr), Some(size)) = (vaddr, size) else {
+        return Ok(vec![]);
+    };
+    let word = std::mem::size_of::<usize>() as u64;
+    let mut array = vec![];
+    for i in 0..size / word {
+        let entry = unsafe { *slot(base, mapped, vaddr.wrapping_add(i * word))? };
+        // 0 and -1 are sometimes used as placeholders
+        if entry != 0 && entry != usize::MAX {
+            array.push(entry as *const c_void);
+        }
+    }
+    Ok(array)
+}
+
+/// Collect DT_PREINIT_ARRAY, DT_INIT and DT_INIT_ARRAY in the order they have to run
+pub(crate) fn initializers(
+    elf: &Elf,
+    base: *mut c_void,
+    mapped: &[Range<usize>],
+) -> Result<Vec<*const c_void>> {
+    let mut initializers = read_array(
+        base,
+        mapped,
+        dynamic_entry(elf, DT_PREINIT_ARRAY),
+        dynamic_entry(elf, DT_PREINIT_ARRAYSZ),
+    )?;
+    if let Some(init) = dynamic_entry(elf, DT_INIT) {
+        initializers.push(base.wrapping_add(to_usize(init)?));
+    }
+    initializers.extend(read_array(
+        base,
+        mapped,
+        dynamic_entry(elf, DT_INIT_ARRAY),
+        dynamic_entry(elf, DT_INIT_ARRAYSZ),
+    )?);
+    Ok(initializers)
+}
+
+/// Collect DT_FINI_ARRAY, backwards, and DT_FINI in the order they have to run
+pub(crate) fn finalizers(
+    elf: &Elf,
+    base: *mut c_void,
+    mapped: &[Range<usize>],
+) -> Result<Vec<*const c_void>> {
+    let mut finalizers = read_array(
+        base,
+        mapped,
+        dynamic_entry(elf, DT_FINI_ARRAY),
+        dynamic_entry(elf, DT_FINI_ARRAYSZ),
+    )?;
+    finalizers.reverse();
+    if let Some(fini) = dynamic_entry(elf, DT_FINI) {
+        finalizers.push(base.wrapping_add(to_usize(fini)?));
+    }
+    Ok(finalizers)
+}
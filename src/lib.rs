@@ -0,0 +1,1716 @@
+//! Loader for SHELFs: statically linked, position independent ELFs that are mapped and run from
+//! memory within the current process. See <https://tmpout.sh/1/10/>
+
+#[cfg(not(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    all(target_arch = "x86", feature = "elf32")
+)))]
+compile_error!("only x86_64 and aarch64 are supported, or i686 with the elf32 feature");
+
+mod arch;
+mod decrypt;
+pub mod error;
+mod init;
+mod reloc;
+mod tls;
+mod unpack;
+
+use goblin::elf::header::{ET_DYN, ET_EXEC};
+#[cfg(target_pointer_width = "32")]
+use goblin::elf::program_header::program_header32::{ProgramHeader, SIZEOF_PHDR};
+#[cfg(target_pointer_width = "64")]
+use goblin::elf::program_header::program_header64::{ProgramHeader, SIZEOF_PHDR};
+use goblin::elf::program_header::{pt_to_str, PF_R, PF_W, PF_X, PT_DYNAMIC, PT_LOAD, PT_TLS};
+use goblin::elf::section_header::{SHN_ABS, SHN_UNDEF};
+use goblin::elf::sym::{Symtab, STB_LOCAL, STT_FUNC, STT_NOTYPE, STT_OBJECT};
+use goblin::strtab::Strtab;
+use goblin::{elf::Elf, Object};
+use libc::{
+    c_void, mmap, mprotect, munmap, sysconf, _SC_CLK_TCK, _SC_PAGESIZE, MAP_ANONYMOUS, MAP_FAILED,
+    MAP_FIXED_NOREPLACE, MAP_PRIVATE, MAP_STACK, PROT_EXEC, PROT_GROWSDOWN, PROT_NONE, PROT_READ,
+    PROT_WRITE,
+};
+use log::{debug, info};
+use std::borrow::Cow;
+use std::convert::Infallible;
+use std::ffi::{CStr, CString};
+use std::fs::File;
+use std::io::Read;
+use std::mem::ManuallyDrop;
+use std::ops::Range;
+use std::os::fd::{AsFd, AsRawFd, FromRawFd};
+use std::os::raw::c_char;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::os::unix::fs::FileExt;
+use std::os::unix::process::ExitStatusExt;
+use std::panic::AssertUnwindSafe;
+use std::path::Path;
+use std::process::ExitStatus;
+
+pub use decrypt::DecryptScheme;
+pub use error::{LoaderError, Result};
+pub use tls::setup_tls;
+
+const AT_NULL: usize = 0;
+const AT_PHDR: usize = 3;
+const AT_PHENT: usize = 4;
+const AT_PHNUM: usize = 5;
+const AT_PAGESZ: usize = 6;
+const AT_BASE: usize = 7;
+const AT_FLAGS: usize = 8;
+const AT_ENTRY: usize = 9;
+const AT_UID: usize = 11;
+const AT_EUID: usize = 12;
+const AT_GID: usize = 13;
+const AT_EGID: usize = 14;
+const AT_PLATFORM: usize = 15;
+const AT_HWCAP: usize = 16;
+const AT_CLKTCK: usize = 17;
+const AT_SECURE: usize = 23;
+const AT_RANDOM: usize = 25;
+const AT_HWCAP2: usize = 26;
+const AT_EXECFN: usize = 31;
+const AT_SYSINFO_EHDR: usize = 33;
+const AT_MINSIGSTKSZ: usize = 51;
+
+/// e_machine of SHELFs the loader can run
+#[cfg(target_arch = "x86_64")]
+const EM_HOST: u16 = goblin::elf::header::EM_X86_64;
+#[cfg(target_arch = "aarch64")]
+const EM_HOST: u16 = goblin::elf::header::EM_AARCH64;
+#[cfg(target_arch = "x86")]
+const EM_HOST: u16 = goblin::elf::header::EM_386;
+
+/// Where a randomized image base is picked from. This leaves the bottom 4 GiB to the loader's
+/// own image and heap and stays below the stack, the vDSO and the kernel's mmap area.
+#[cfg(target_arch = "x86_64")]
+const ASLR_RANGE: Range<usize> = 1 << 32..1 << 46;
+/// Small enough for kernels with 39-bit virtual addresses
+#[cfg(target_arch = "aarch64")]
+const ASLR_RANGE: Range<usize> = 1 << 32..1 << 38;
+#[cfg(target_arch = "x86")]
+const ASLR_RANGE: Range<usize> = 0x1000_0000..0xb000_0000;
+
+/// How many random addresses are tried before falling back to the kernel's choice
+const ASLR_ATTEMPTS: usize = 8;
+
+/// Size of the stack mapped for the SHELF when the loader's own isn't reused
+const STACK_SIZE: usize = 8 << 20;
+
+/// An auxiliary vector entry
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct ElfAuxv {
+    pub key: usize,
+    pub value: usize,
+}
+
+/// Views into the process's initial stack as set up by the kernel
+#[derive(Debug)]
+pub struct Stack {
+    pub argc: *mut usize,
+    /// Includes the NULL terminator
+    pub argv: &'static mut [*const c_char],
+    /// Includes the NULL terminator
+    pub envp: &'static mut [*const c_char],
+    /// Includes the AT_NULL terminator
+    pub auxv: &'static mut [ElfAuxv],
+}
+
+impl Stack {
+    /// End of the initial vector. The strings argv and envp point to live above this
+    pub fn end(&self) -> *mut usize {
+        self.auxv.as_ptr_range().end as *mut usize
+    }
+}
+
+/// Builder to load a SHELF from memory and run it
+pub struct Loader<'a> {
+    raw_file: Cow<'a, [u8]>,
+    args: Option<Vec<String>>,
+    env: Option<Vec<(String, String)>>,
+    rewrite_argv: bool,
+    run_init: bool,
+    reuse_stack: bool,
+    aslr: bool,
+    relro: bool,
+    relr: bool,
+    scrub: bool,
+    decrypt: Option<DecryptScheme>,
+    before_exec: Option<BeforeExec<'a>>,
+}
+
+// Callback set with Loader::on_before_exec
+type BeforeExec<'a> = Box<dyn FnOnce(&mut ExecContext) + 'a>;
+
+/// What the SHELF is about to start with, handed to [`Loader::on_before_exec`]
+#[derive(Debug)]
+pub struct ExecContext<'a> {
+    /// Address that's about to be jumped to
+    pub entry: *const c_void,
+    /// Address vaddr 0 of the image is mapped at
+    pub base: *mut c_void,
+    /// Where the vector will be copied to, the SHELF's initial stack pointer
+    pub stack_pointer: *const usize,
+    /// argc, argv, envp and auxv as they'll appear at `stack_pointer`, before any changes to
+    /// `auxv`
+    pub vector: &'a [usize],
+    /// The auxiliary vector within `vector`, ending with AT_NULL. The SHELF gets any values
+    /// changed here, `vector` is rebuilt from it.
+    pub auxv: &'a mut [ElfAuxv],
+    /// Thread pointer to be installed, null if the SHELF sets up its own
+    pub thread_pointer: *mut c_void,
+}
+
+// The SHELF's initial stack before its vector is built: where the vector ends and what goes in it.
+// argv and envp don't include their NULL terminators, auxv ends with AT_NULL.
+struct InitialStack {
+    end: *mut usize,
+    argv: Vec<*const c_char>,
+    envp: Vec<*const c_char>,
+    auxv: Vec<ElfAuxv>,
+    /// NUL-terminated path of the SHELF for AT_EXECFN, null if there's no argv[0] to take it from
+    execfn: *const c_char,
+}
+
+/// A SHELF mapped into memory and ready to be jumped to. Dropping it unmaps the SHELF and its TLS
+/// block, so it has to outlive any use of their addresses. One that's been run is never dropped.
+#[derive(Debug)]
+pub struct MappedImage {
+    /// Address vaddr 0 of the image is mapped at
+    pub base: *mut c_void,
+    pub entry: *mut c_void,
+    /// The TLS and DYNAMIC program headers copied into the image for the SHELF's startup code, or
+    /// the full program header table if it has an interpreter
+    pub phdr: *const c_void,
+    pub phnum: usize,
+    /// Constructors from DT_PREINIT_ARRAY, DT_INIT and DT_INIT_ARRAY in the order they run
+    pub initializers: Vec<*const c_void>,
+    /// Destructors from DT_FINI_ARRAY and DT_FINI in the order they run. The loader never calls
+    /// them: a static libc runs its own at exit, and the SHELF gets no atexit function from us as
+    /// glibc would run them a second time through it.
+    pub finalizers: Vec<*const c_void>,
+    /// Thread pointer for the SHELF's static TLS, null if it has no PT_TLS segment
+    pub thread_pointer: *mut c_void,
+    /// IRELATIVE slots and the IFUNC resolvers that fill them. Resolvers are SHELF code, so
+    /// [`Loader::exec_at`] only calls them right before the jump and mapping runs nothing.
+    pub ifuncs: Vec<(*mut usize, *const c_void)>,
+    /// The PT_LOAD segments where they were mapped
+    pub segments: Vec<Segment>,
+    /// Symbols the SHELF defines, from .symtab or .dynsym if it's been stripped
+    pub symbols: Vec<Symbol>,
+    /// Symbols the SHELF exports through .dynsym
+    pub exports: Vec<Symbol>,
+    /// The dynamic linker named by PT_INTERP, which is jumped to instead of the SHELF's entry
+    pub interpreter: Option<Box<MappedImage>>,
+    /// Whether PT_GNU_STACK asks for an executable stack. A missing PT_GNU_STACK means it doesn't.
+    pub exec_stack: bool,
+    /// The PT_GNU_RELRO range, if the loader relocated the SHELF and it has one
+    pub relro: Option<Segment>,
+    /// The PT_TLS initialization image within the mapped SHELF
+    pub tls: Option<Segment>,
+    /// The PT_DYNAMIC section within the mapped SHELF
+    pub dynamic: Option<Segment>,
+    // The image's reservation and TLS block, only held on to be unmapped along with it
+    _mappings: Vec<OwnedMapping>,
+}
+
+/// A segment of a mapped SHELF
+#[derive(Debug, Clone)]
+pub struct Segment {
+    /// Where the start of the segment was mapped
+    pub addr: *mut c_void,
+    /// Size in memory, including .bss
+    pub len: usize,
+    /// PF_R, PF_W and PF_X from the program header
+    pub flags: u32,
+    /// p_type of the program header, e.g. PT_LOAD
+    pub p_type: u32,
+    /// Offset of the segment's contents in the file
+    pub offset: usize,
+}
+
+impl MappedImage {
+    /// Runtime address of the exported symbol `name`, None if the SHELF doesn't export it
+    pub fn symbol(&self, name: &str) -> Option<*const c_void> {
+        self.exports
+            .iter()
+            .find(|sym| sym.name == name)
+            .map(|sym| sym.addr as *const c_void)
+    }
+
+    // Call the IFUNC resolvers map left for when the SHELF runs anyway. Every segment is made
+    // writable and executable for them, then given its own permissions back, RELRO included.
+    unsafe fn resolve_ifuncs(&self, relro: bool) -> Result<()> {
+        if self.ifuncs.is_empty() {
+            return Ok(());
+        }
+        for segment in &self.segments {
+            let (start, len) = page_align(segment.addr as usize, segment.len)?;
+            protect(start, len, PROT_READ | PROT_WRITE | PROT_EXEC)?;
+        }
+        reloc::resolve_ifuncs(&self.ifuncs);
+        protect_segments(&self.segments)?;
+        if relro {
+            self.protect_relro()?;
+        }
+        Ok(())
+    }
+
+    /// Make the PT_GNU_RELRO range read-only. Like ld.so, the end is rounded down to a page so
+    /// data sharing the last page stays writable.
+    pub fn protect_relro(&self) -> Result<()> {
+        let Some(relro) = &self.relro else {
+            return Ok(());
+        };
+        let page_size = page_size();
+        let start = relro.addr as usize & !(page_size - 1);
+        let end = segment_end(relro.addr as usize, relro.len)? & !(page_size - 1);
+        if start < end {
+            protect(start, end - start, PROT_READ)?;
+        }
+        Ok(())
+    }
+
+    /// A `/proc/self/maps` style table of the image: one line per LOAD, TLS and DYNAMIC segment
+    /// with its address range, permissions, file offset and type, and then the entry point. The
+    /// auxv entries the loader points at the SHELF follow, then the interpreter's segments and
+    /// entry point if there is one.
+    pub fn describe(&self) -> String {
+        let mut out = self.describe_segments();
+        let interpreter_base = self
+            .interpreter
+            .as_ref()
+            .map_or(0, |interpreter| interpreter.base as usize);
+        out += &format!("AT_PHDR {:#x}\n", self.phdr as usize);
+        out += &format!("AT_PHENT {}\n", SIZEOF_PHDR);
+        out += &format!("AT_PHNUM {}\n", self.phnum);
+        out += &format!("AT_BASE {:#x}\n", interpreter_base);
+        out += &format!("AT_ENTRY {:#x}\n", self.entry as usize);
+        if let Some(interpreter) = &self.interpreter {
+            out += "interpreter\n";
+            out += &interpreter.describe_segments();
+        }
+        out
+    }
+
+    // The segment table and entry point for describe
+    fn describe_segments(&self) -> String {
+        let mut out = String::new();
+        let mut segments: Vec<&Segment> = self.segments.iter().collect();
+        segments.extend(self.tls.iter().chain(self.dynamic.iter()));
+        segments.sort_by_key(|segment| (segment.addr as usize, segment.p_type != PT_LOAD));
+        for segment in segments {
+            let start = segment.addr as usize;
+            let perms = [(PF_R, 'r'), (PF_W, 'w'), (PF_X, 'x')]
+                .iter()
+                .map(|&(flag, c)| if segment.flags & flag != 0 { c } else { '-' })
+                .collect::<String>();
+            out += &format!(
+                "{:08x}-{:08x} {}p {:08x} {}\n",
+                start,
+                start.wrapping_add(segment.len),
+                perms,
+                segment.offset,
+                pt_to_str(segment.p_type).trim_start_matches("PT_")
+            );
+        }
+        out += &format!("entry {:#x}\n", self.entry as usize);
+        out
+    }
+}
+
+/// A symbol defined by a mapped SHELF
+#[derive(Debug, Clone)]
+pub struct Symbol {
+    pub name: String,
+    /// Runtime address of the symbol
+    pub addr: *mut c_void,
+    pub size: usize,
+}
+
+/// Get pointers to the initial stack frame for manipulation and reuse in the SHELF
+///
+/// # Safety
+///
+/// This walks the raw process stack starting from `environ`, so `environ` must still point at
+/// the envp array the kernel set up (i.e. nothing has called `setenv` and friends since startup).
+/// The returned slices alias that stack memory and are only valid until the process hands it
+/// over to the SHELF.
+pub unsafe fn get_initial_stack() -> Stack {
+    // Use environ from libc to find envp.
+    // Technique taken from https://docs.rs/auxv/latest/src/auxv/stack.rs.html#69
+    extern "C" {
+        static environ: *const *const c_char;
+    }
+    let envp = unsafe { environ } as *mut *const c_char;
+
+    // As detailed @ https://articles.manugarg.com/aboutelfauxiliaryvectors.html.
+    // The initial stack looks like:
+    // position            content                     size (bytes) + comment
+    // ------------------------------------------------------------------------
+    // stack pointer ->  [ argc = number of args ]     4
+    //                   [ argv[0]  (pointer) ]        4   (program name)
+    //                   [ argv[..] (pointer) ]        4
+    //                   [ argv[n]  (pointer) ]        4   (= NULL)
+    //                   [ envp[0]  (pointer) ]        4
+    //                   [ envp[..] (pointer) ]        4
+    //                   [ envp[m]  (pointer) ]        4   (= NULL)
+    //                   [ auxv[0]  (Elf32_auxv_t) ]   8
+    //                   [ auxv[1]  (Elf32_auxv_t) ]   8
+    //                   [ auxv[..] (Elf32_auxv_t) ]   8
+    //                   [ auxv[l]  (Elf32_auxv_t) ]   8   (= AT_NULL vector)
+    //                   [ padding ]                   0 - 16
+    //                   [ argument ASCIIZ strings ]   >= 0
+    //                   [ environment ASCIIZ str. ]   >= 0
+    //   (0xbffffffc)    [ end marker ]                4   (= NULL)
+    //   (0xc0000000)    < bottom of stack >           0   (virtual)
+    //   ------------------------------------------------------------------------
+    //
+    // As we already know envp, we can search for the null terminator to find auxv.
+    // This technique was also learned from the auxv crate.
+    // To find argv and argc we can traverse down the stack (up the graphic) until the value at our
+    // pointer equals the number of args we have iterated over.
+
+    // Find auxv and get the size of envp
+    let mut auxv = envp;
+    let mut envp_len = 0;
+    unsafe {
+        // Increment until we find the end of envp
+        while !(*auxv).is_null() {
+            auxv = auxv.add(1);
+            envp_len += 1;
+        }
+        // Add one for the NULL
+        auxv = auxv.add(1);
+        envp_len += 1;
+    }
+    let auxv = auxv as *mut ElfAuxv;
+
+    // Find the auxv length
+    let auxv_len = unsafe {
+        let mut auxv_iter = auxv;
+        let mut len = 0;
+        while (*auxv_iter).key != AT_NULL {
+            auxv_iter = auxv_iter.add(1);
+            len += 1;
+        }
+        // Add one for the AT_NULL
+        len + 1
+    };
+
+    // Find argv and argc
+    let mut argc: *mut usize = unsafe { envp.sub(2) as *mut usize };
+    let mut argv_len = 0;
+    let argv = unsafe {
+        // This may fail if strings in argv are placed in a very low page and argv is huge
+        // ... but that sounds unlikely and like someone else's problem
+        while *argc != argv_len {
+            argc = argc.sub(1);
+            argv_len += 1;
+        }
+        argv_len += 1;
+        argc.add(1) as *mut *const c_char
+    };
+    unsafe {
+        Stack {
+            argc,
+            argv: std::slice::from_raw_parts_mut(argv, argv_len),
+            envp: std::slice::from_raw_parts_mut(envp, envp_len),
+            auxv: std::slice::from_raw_parts_mut(auxv, auxv_len),
+        }
+    }
+}
+
+/// Point the auxv entries describing the program at the SHELF instead of the loader.
+/// AT_PHENT is the size of the loader's own program header class, which the SHELF must share.
+/// AT_BASE is the base of the SHELF's interpreter, or 0 if it doesn't have one.
+///
+/// AT_RANDOM gets 16 fresh bytes from getrandom so the SHELF doesn't seed its stack canary and
+/// pointer guard from the same bytes as the loader. The bytes are leaked for the SHELF to keep.
+///
+/// AT_SYSINFO_EHDR is set to the loader's own vDSO, which stays mapped for the SHELF to use.
+///
+/// AT_EXECFN is pointed at `execfn`, the SHELF's path, unless it's null.
+pub fn setup_auxv(auxv: &mut [ElfAuxv], image: &MappedImage, execfn: *const c_char) -> Result<()> {
+    let interpreter_base = image
+        .interpreter
+        .as_ref()
+        .map_or(0, |interpreter| interpreter.base as usize);
+    // Update auxv values with our SHELF's new values
+    for aux in auxv {
+        match aux.key {
+            AT_PHDR => aux.value = image.phdr as usize,
+            AT_PHNUM => aux.value = image.phnum,
+            AT_PHENT => aux.value = SIZEOF_PHDR,
+            AT_BASE => aux.value = interpreter_base,
+            AT_ENTRY => aux.value = image.entry as usize,
+            AT_RANDOM => aux.value = Box::leak(random_bytes()?).as_ptr() as usize,
+            AT_SYSINFO_EHDR => {
+                aux.value = unsafe { libc::getauxval(AT_SYSINFO_EHDR as _) } as usize
+            }
+            AT_EXECFN if !execfn.is_null() => aux.value = execfn as usize,
+            _ => (),
+        }
+    }
+    Ok(())
+}
+
+// 16 bytes from getrandom for AT_RANDOM
+fn random_bytes() -> Result<Box<[u8; 16]>> {
+    let mut bytes = Box::new([0; 16]);
+    let ret = unsafe { libc::getrandom(bytes.as_mut_ptr() as *mut c_void, bytes.len(), 0) };
+    if ret != bytes.len() as isize {
+        return Err(LoaderError::GetRandom(std::io::Error::last_os_error()));
+    }
+    Ok(bytes)
+}
+
+// Translate ELF segment flags into mmap protection bits
+fn flags_to_prot(p_flags: u32) -> i32 {
+    let mut prot = PROT_NONE;
+    if p_flags & PF_R != 0 {
+        prot |= PROT_READ;
+    }
+    if p_flags & PF_W != 0 {
+        prot |= PROT_WRITE;
+    }
+    if p_flags & PF_X != 0 {
+        prot |= PROT_EXEC;
+    }
+    prot
+}
+
+fn page_size() -> usize {
+    unsafe { sysconf(_SC_PAGESIZE) as usize }
+}
+
+// Round the range [addr, addr + len) out to page boundaries, failing if that runs past the end of
+// the address space. Returns the aligned address and length
+fn page_align(addr: usize, len: usize) -> Result<(usize, usize)> {
+    let page_size = page_size();
+    let start = addr & !(page_size - 1);
+    let end = segment_end(addr, len)?
+        .checked_add(page_size - 1)
+        .ok_or(LoaderError::BadOffset)?
+        & !(page_size - 1);
+    Ok((start, end - start))
+}
+
+fn protect(addr: usize, len: usize, prot: i32) -> Result<()> {
+    let ret = unsafe { mprotect(addr as *mut c_void, len, prot) };
+    if ret != 0 {
+        return Err(LoaderError::Mprotect(std::io::Error::last_os_error()));
+    }
+    Ok(())
+}
+
+// Convert a header field to usize, failing on values the address space can't hold
+fn to_usize(value: u64) -> Result<usize> {
+    value.try_into().map_err(|_| LoaderError::BadOffset)
+}
+
+// Give the pages each loadable segment covers the same protection, leaving any gaps between them
+// inaccessible
+fn protect_loads(
+    base: *mut c_void,
+    load_phdrs: &[&goblin::elf::ProgramHeader],
+    prot: i32,
+) -> Result<()> {
+    for h in load_phdrs {
+        let addr = (base as usize).wrapping_add(to_usize(h.p_vaddr)?);
+        let (start, len) = page_align(addr, to_usize(h.p_memsz)?)?;
+        protect(start, len, prot)?;
+    }
+    Ok(())
+}
+
+// Apply each loadable segment's permissions to the pages it covers.
+// When two segments share a page, that page gets the permissions of both.
+fn protect_segments(segments: &[Segment]) -> Result<()> {
+    let mut segments: Vec<(usize, usize, i32)> = segments
+        .iter()
+        .map(|segment| {
+            let (start, len) = page_align(segment.addr as usize, segment.len)?;
+            Ok((start, start + len, flags_to_prot(segment.flags)))
+        })
+        .collect::<Result<_>>()?;
+    segments.sort_by_key(|&(start, _, _)| start);
+
+    let mut prev_end = 0;
+    let mut prev_prot = PROT_NONE;
+    for (mut start, end, prot) in segments {
+        if start < prev_end {
+            // The first page(s) belong to the previous segment too
+            let shared_end = prev_end.min(end);
+            protect(start, shared_end - start, prot | prev_prot)?;
+            start = shared_end;
+        }
+        if start < end {
+            protect(start, end - start, prot)?;
+        }
+        prev_end = prev_end.max(end);
+        prev_prot = prot;
+    }
+    Ok(())
+}
+
+/// Lay out argc, argv, envp and auxv the way the SHELF expects to find them at its stack pointer.
+/// argv and envp don't include their NULL terminators, auxv is expected to end with AT_NULL
+pub fn build_stack_vector(
+    argv: &[*const c_char],
+    envp: &[*const c_char],
+    auxv: &[ElfAuxv],
+) -> Vec<usize> {
+    let mut vector = Vec::with_capacity(argv.len() + envp.len() + auxv.len() * 2 + 3);
+    vector.push(argv.len());
+    vector.extend(argv.iter().map(|&arg| arg as usize));
+    vector.push(0);
+    vector.extend(envp.iter().map(|&var| var as usize));
+    vector.push(0);
+    for aux in auxv {
+        vector.push(aux.key);
+        vector.push(aux.value);
+    }
+    vector
+}
+
+// The loader's own argv as the SHELF gets it when no args are set.
+// Rewriting passes argv[1..] so the SHELF's path becomes its argv[0]. If nothing follows the
+// loader's argv[0] it's kept instead, so the SHELF never starts with an empty argv.
+fn host_args<T: Clone>(host_argv: &[T], rewrite_argv: bool) -> Vec<T> {
+    match host_argv {
+        [_, rest @ ..] if rewrite_argv && !rest.is_empty() => rest.to_vec(),
+        _ => host_argv.to_vec(),
+    }
+}
+
+// NUL-terminated copy of s
+fn c_string(s: Vec<u8>) -> Result<CString> {
+    CString::new(s).map_err(|err| {
+        LoaderError::InvalidString(String::from_utf8_lossy(&err.into_vec()).into_owned())
+    })
+}
+
+// Leak a NUL-terminated copy of s for the SHELF to keep pointing at
+fn leak_c_string(s: &str) -> Result<*const c_char> {
+    Ok(c_string(s.into())?.into_raw())
+}
+
+// Zero buffer before freeing it. Volatile writes so the zeroing isn't optimized out as dead.
+fn scrub_buffer(mut buffer: Vec<u8>) {
+    for byte in buffer.iter_mut() {
+        unsafe { std::ptr::write_volatile(byte, 0) };
+    }
+}
+
+// Build a new auxv for a fresh stack, AT_NULL included. The entries go in the order the kernel
+// uses. The ones describing the program are left 0 for setup_auxv, the rest are the loader's own
+// values as the SHELF runs in the same process. Entries the kernel only sometimes passes are left
+// out when the loader didn't get them either.
+fn build_auxv() -> Vec<ElfAuxv> {
+    let host = |key: usize| unsafe { libc::getauxval(key as _) } as usize;
+    let pagesz = page_size();
+    let clktck = unsafe { sysconf(_SC_CLK_TCK) } as usize;
+    let (uid, euid, gid, egid) = unsafe {
+        (
+            libc::getuid() as usize,
+            libc::geteuid() as usize,
+            libc::getgid() as usize,
+            libc::getegid() as usize,
+        )
+    };
+    let entries = [
+        (AT_SYSINFO_EHDR, host(AT_SYSINFO_EHDR), false),
+        (AT_MINSIGSTKSZ, host(AT_MINSIGSTKSZ), false),
+        (AT_HWCAP, host(AT_HWCAP), true),
+        (AT_PAGESZ, pagesz, true),
+        (AT_CLKTCK, clktck, true),
+        (AT_PHDR, 0, true),
+        (AT_PHENT, 0, true),
+        (AT_PHNUM, 0, true),
+        (AT_BASE, 0, true),
+        (AT_FLAGS, 0, true),
+        (AT_ENTRY, 0, true),
+        (AT_UID, uid, true),
+        (AT_EUID, euid, true),
+        (AT_GID, gid, true),
+        (AT_EGID, egid, true),
+        (AT_SECURE, host(AT_SECURE), true),
+        (AT_RANDOM, 0, true),
+        (AT_HWCAP2, host(AT_HWCAP2), false),
+        (AT_EXECFN, 0, true),
+        (AT_PLATFORM, host(AT_PLATFORM), false),
+        (AT_NULL, 0, true),
+    ];
+    entries
+        .into_iter()
+        .filter(|&(_, value, always)| always || value != 0)
+        .map(|(key, value, _)| ElfAuxv { key, value })
+        .collect()
+}
+
+// Protection for the SHELF's stack, executable only if PT_GNU_STACK asks for it
+fn stack_prot(exec_stack: bool) -> i32 {
+    if exec_stack {
+        PROT_READ | PROT_WRITE | PROT_EXEC
+    } else {
+        PROT_READ | PROT_WRITE
+    }
+}
+
+// Map a stack of size bytes for the SHELF with a guard page at the bottom and return its top
+fn map_stack(size: usize, prot: i32) -> Result<*mut u8> {
+    let stack = unsafe {
+        mmap(
+            std::ptr::null_mut(),
+            size,
+            prot,
+            MAP_PRIVATE | MAP_ANONYMOUS | MAP_STACK,
+            -1,
+            0,
+        )
+    };
+    if stack == MAP_FAILED {
+        return Err(LoaderError::Mmap(std::io::Error::last_os_error()));
+    }
+    protect(stack as usize, page_size(), PROT_NONE)?;
+    Ok(stack.wrapping_add(size) as *mut u8)
+}
+
+// Copy s onto the stack just below top, moving top down past it
+unsafe fn push_string(top: &mut *mut u8, s: &CStr) -> *const c_char {
+    let bytes = s.to_bytes_with_nul();
+    *top = top.sub(bytes.len());
+    std::ptr::copy_nonoverlapping(bytes.as_ptr(), *top, bytes.len());
+    *top as *const c_char
+}
+
+/// Copy the initial stack vector so that it ends at or just below `stack_end`, point the stack
+/// pointer at it and jump to the SHELF. The copy is done in asm as it may overwrite our own stack
+/// frames.
+///
+/// The vector is placed so the stack pointer is 16-byte aligned at the entry point as the ABIs
+/// require, leaving up to 15 bytes of padding between it and `stack_end`.
+///
+/// If `thread_pointer` isn't null it's installed first (%fs on x86-64, TPIDR_EL0 on aarch64,
+/// %gs on i386). That also happens in asm as the loader's own TLS is unusable from then on.
+///
+/// `initializers` are then called in order with argc, argv and envp from the copied vector, see
+/// [`MappedImage::initializers`]. They run on the SHELF's stack and thread pointer so they can
+/// use its TLS.
+///
+/// Every other general purpose register is zeroed before the jump, so the SHELF's `_start` sees no
+/// atexit function in rdx (x0 on aarch64, edx on i386). Its destructors are left to its own libc,
+/// see [`MappedImage::finalizers`].
+///
+/// This never returns: the SHELF owns the stack and thread from the jump on, and the frames we
+/// would return into may have been overwritten by the copy.
+///
+/// # Safety
+///
+/// `stack_end` must be the top of writable memory large enough to hold `vector` and whatever the
+/// SHELF goes on to use as its stack, and `entry` and each initializer must point at mapped,
+/// executable code. `initializers` must not live on the stack below `stack_end`, anything still
+/// alive there is clobbered.
+pub unsafe fn exec_shelf(
+    entry: *const c_void,
+    stack_end: *mut usize,
+    vector: &[usize],
+    thread_pointer: *mut c_void,
+    initializers: &[*const c_void],
+) -> ! {
+    let sp = initial_sp(stack_end, vector.len()) as *mut usize;
+    arch::jump(
+        entry,
+        sp,
+        vector.as_ptr(),
+        vector.len(),
+        thread_pointer,
+        initializers.as_ptr(),
+        initializers.len(),
+    )
+}
+
+// Where a vector of len words ends up below stack_end, keeping the ABI's 16 byte alignment
+fn initial_sp(stack_end: *mut usize, len: usize) -> *const usize {
+    (stack_end.wrapping_sub(len) as usize & !15) as *const usize
+}
+
+// Reserve len bytes for the image starting on an align boundary, wherever the kernel puts it or
+// at a random address with aslr. Nothing in it is accessible until map_segments commits the pages
+// each segment covers.
+fn map_image(len: usize, align: usize, aslr: bool) -> Result<*mut c_void> {
+    if aslr {
+        for _ in 0..ASLR_ATTEMPTS {
+            let Some(addr) = random_address(len, align)? else {
+                break;
+            };
+            // MAP_FIXED_NOREPLACE fails instead of replacing any of the loader's own mappings.
+            // Kernels before 4.17 take it as a hint and may map somewhere else, which is fine too
+            // as long as it's aligned.
+            let mapping = unsafe {
+                mmap(
+                    addr as *mut c_void,
+                    len,
+                    PROT_NONE,
+                    MAP_PRIVATE | MAP_ANONYMOUS | MAP_FIXED_NOREPLACE,
+                    -1,
+                    0,
+                )
+            };
+            if mapping != MAP_FAILED {
+                if mapping as usize & (align - 1) == 0 {
+                    return Ok(mapping);
+                }
+                unsafe { munmap(mapping, len) };
+            }
+        }
+        // Out of luck, let the kernel choose
+    }
+    // The kernel only page aligns, so reserve enough to trim down to an aligned start
+    let padded = len
+        .checked_add(align - page_size())
+        .ok_or(LoaderError::BadOffset)?;
+    let mapping = unsafe {
+        mmap(
+            std::ptr::null_mut(),
+            padded,
+            PROT_NONE,
+            MAP_PRIVATE | MAP_ANONYMOUS,
+            -1,
+            0,
+        )
+    };
+    if mapping == MAP_FAILED {
+        return Err(LoaderError::Mmap(std::io::Error::last_os_error()));
+    }
+    let start = (mapping as usize).wrapping_add(align - 1) & !(align - 1);
+    let end = start + len;
+    unsafe {
+        if start > mapping as usize {
+            munmap(mapping, start - mapping as usize);
+        }
+        if end < mapping as usize + padded {
+            munmap(end as *mut c_void, mapping as usize + padded - end);
+        }
+    }
+    Ok(start as *mut c_void)
+}
+
+// Memory the loader mmap'd for an image, unmapped when it's dropped
+#[derive(Debug)]
+pub(crate) struct OwnedMapping {
+    pub(crate) addr: *mut c_void,
+    pub(crate) len: usize,
+}
+
+impl Drop for OwnedMapping {
+    fn drop(&mut self) {
+        unsafe { munmap(self.addr, self.len) };
+    }
+}
+
+// Reserve len bytes at exactly addr for an ELF that can't be moved.
+// Fails rather than replacing whatever the loader already has mapped there.
+fn map_fixed(addr: usize, len: usize) -> Result<*mut c_void> {
+    let mapping = unsafe {
+        mmap(
+            addr as *mut c_void,
+            len,
+            PROT_NONE,
+            MAP_PRIVATE | MAP_ANONYMOUS | MAP_FIXED_NOREPLACE,
+            -1,
+            0,
+        )
+    };
+    if mapping == MAP_FAILED {
+        let err = std::io::Error::last_os_error();
+        return Err(match err.raw_os_error() {
+            Some(libc::EEXIST) => LoaderError::AddressInUse(addr),
+            _ => LoaderError::Mmap(err),
+        });
+    }
+    // Kernels before 4.17 take MAP_FIXED_NOREPLACE as a hint and map somewhere else instead
+    if mapping as usize != addr {
+        unsafe { munmap(mapping, len) };
+        return Err(LoaderError::AddressInUse(addr));
+    }
+    Ok(mapping)
+}
+
+// A random align aligned address in ASLR_RANGE with room for len bytes after it, if they fit at
+// all. align is a power of two of at least a page.
+fn random_address(len: usize, align: usize) -> Result<Option<usize>> {
+    let start = ASLR_RANGE.start.next_multiple_of(align);
+    let Some(room) = ASLR_RANGE
+        .end
+        .checked_sub(start)
+        .and_then(|range| range.checked_sub(len))
+    else {
+        return Ok(None);
+    };
+    let slots = room / align + 1;
+    let bytes = random_bytes()?;
+    let random = usize::from_ne_bytes(bytes[..std::mem::size_of::<usize>()].try_into().unwrap());
+    Ok(Some(start + random % slots * align))
+}
+
+// Check the ELF is something the loader can run in this process
+fn check_header(elf: &Elf) -> Result<()> {
+    // The SHELF runs in our process so it has to have the same pointer width.
+    // That also means the phdrs copied into the image use the loader's own header layout.
+    if elf.is_64 != cfg!(target_pointer_width = "64") {
+        return Err(LoaderError::UnsupportedArch);
+    }
+    // Nor can it use headers and data in the other byte order
+    if elf.little_endian != cfg!(target_endian = "little") {
+        return Err(LoaderError::EndianMismatch);
+    }
+    // Object files and core dumps have nothing to run
+    if !matches!(elf.header.e_type, ET_DYN | ET_EXEC) {
+        return Err(LoaderError::UnsupportedElfType(elf.header.e_type));
+    }
+    if elf.header.e_machine != EM_HOST {
+        return Err(LoaderError::ArchMismatch {
+            expected: EM_HOST,
+            found: elf.header.e_machine,
+        });
+    }
+    // Whatever gets jumped to has to be code the ELF loads
+    let in_code = elf.program_headers.iter().any(|h| {
+        h.p_type == goblin::elf::program_header::PT_LOAD
+            && h.p_flags & PF_X != 0
+            && elf.entry >= h.p_vaddr
+            && elf.entry - h.p_vaddr < h.p_memsz
+    });
+    if !in_code {
+        return Err(LoaderError::OutOfBounds(elf.entry));
+    }
+    Ok(())
+}
+
+// The alignment a PIE's base needs: the largest p_align of its loadable segments, and at least a
+// page. Values that aren't a power of two are ignored like 0 and 1.
+fn load_align(load_phdrs: &[&goblin::elf::ProgramHeader]) -> usize {
+    load_phdrs
+        .iter()
+        .filter_map(|h| usize::try_from(h.p_align).ok())
+        .filter(|align| align.is_power_of_two())
+        .fold(page_size(), usize::max)
+}
+
+// Check [vaddr, vaddr + len) lies within the pages committed to the loadable segments. Anywhere
+// else in the span, like a gap between segments, is PROT_NONE.
+fn check_mapped(mapped: &[Range<usize>], vaddr: usize, len: usize) -> Result<()> {
+    let end = segment_end(vaddr, len)?;
+    if !mapped
+        .iter()
+        .any(|range| vaddr >= range.start && end <= range.end)
+    {
+        return Err(LoaderError::BadOffset);
+    }
+    Ok(())
+}
+
+// Reserve the image and copy the loadable segments into it, zeroing .bss.
+// With fixed (ET_EXEC) the segments go at their own vaddrs, otherwise wherever map_image says.
+// Returns the image base and the page rounded vaddr ranges committed to the segments, still
+// writable. Segments on adjoining pages get one range so an object may straddle them.
+fn map_segments(
+    load_phdrs: &[&goblin::elf::ProgramHeader],
+    raw_file: &[u8],
+    aslr: bool,
+    fixed: bool,
+) -> Result<(*mut c_void, Vec<Range<usize>>, OwnedMapping)> {
+    if load_phdrs.is_empty() {
+        return Err(LoaderError::NoLoadableSegment);
+    }
+
+    // Only the span from the lowest to the highest address is reserved, rounded out to whole pages.
+    // For a PIE it starts on the largest p_align too, so that placing the reservation on that
+    // alignment keeps the image base on it. Where vaddr 0 would be is worked out from it below.
+    // Each segment's file contents are checked against the file before anything is mapped.
+    let mut ranges: Vec<Range<usize>> = Vec::with_capacity(load_phdrs.len());
+    for h in load_phdrs.iter() {
+        let load_vaddr = to_usize(h.p_vaddr)?;
+        let mem_size = to_usize(h.p_memsz)?;
+        if h.p_filesz > h.p_memsz {
+            return Err(LoaderError::BadOffset);
+        }
+        if segment_end(to_usize(h.p_offset)?, to_usize(h.p_filesz)?)? > raw_file.len() {
+            return Err(LoaderError::TruncatedFile);
+        }
+        ranges.push(load_vaddr..segment_end(load_vaddr, mem_size)?);
+    }
+    // Segments may share a page, see protect_segments, but one copied over another means a
+    // broken or malicious ELF
+    ranges.sort_by_key(|range| range.start);
+    if ranges.windows(2).any(|pair| pair[0].end > pair[1].start) {
+        return Err(LoaderError::OverlappingSegments);
+    }
+    let start = ranges[0].start;
+    let end = ranges.iter().map(|range| range.end).max().unwrap_or(start);
+    let align = if fixed {
+        page_size()
+    } else {
+        load_align(load_phdrs)
+    };
+    let aligned_start = start & !(align - 1);
+    let (span_start, span_len) = page_align(aligned_start, end - aligned_start)?;
+    let mut mapped: Vec<Range<usize>> = vec![];
+    for range in ranges.iter() {
+        let (page_start, page_len) = page_align(range.start, range.end - range.start)?;
+        match mapped.last_mut() {
+            Some(last) if page_start <= last.end => last.end = last.end.max(page_start + page_len),
+            _ => mapped.push(page_start..page_start + page_len),
+        }
+    }
+
+    // Load the loadable segments
+    let mapping = if fixed {
+        map_fixed(span_start, span_len)?
+    } else {
+        map_image(span_len, align, aslr)?
+    };
+    debug!("mapping: {:?} ({:#x} bytes)", mapping, span_len);
+    // Unmapped again if anything from here on fails
+    let reservation = OwnedMapping {
+        addr: mapping,
+        len: span_len,
+    };
+    let base = unsafe {
+        // Where vaddr 0 of the image ends up, 0 itself for a fixed image. It may wrap around when
+        // the image is linked above where it's mapped, so it's only ever offset with wrapping_add.
+        let base = mapping.wrapping_sub(span_start);
+        // Commit the segments' pages, writable for now so they can be copied in. See the mprotect
+        // pass in map_elf.
+        protect_loads(base, load_phdrs, PROT_READ | PROT_WRITE)?;
+
+        // Copy each loadable segment to its vaddr.
+        // Only the segment's own bytes are copied, not whole pages, so a segment starting mid-page
+        // doesn't clobber the end of a previous segment sharing that page.
+        for load_phdr in load_phdrs.iter() {
+            let load_vaddr = to_usize(load_phdr.p_vaddr)?;
+            let load_offset = to_usize(load_phdr.p_offset)?;
+            let load_filesz = to_usize(load_phdr.p_filesz)?;
+            let mem_size = to_usize(load_phdr.p_memsz)?;
+            let src = &raw_file[load_offset..load_offset + load_filesz];
+            let dst = base.wrapping_add(load_vaddr) as *mut u8;
+            std::ptr::copy_nonoverlapping(src.as_ptr(), dst, src.len());
+            // Zero the rest of the segment (.bss) instead of trusting what the mapping holds
+            if mem_size > load_filesz {
+                std::ptr::write_bytes(dst.add(load_filesz), 0, mem_size - load_filesz);
+            }
+        }
+
+        base
+    };
+    Ok((base, mapped, reservation))
+}
+
+// Where the SHELF's own program header table ended up in the image, for ELFs that get the full
+// table rather than the filtered copy. That's wherever PT_PHDR says, or failing that the loadable
+// segment covering e_phoff.
+fn mapped_phdrs(elf: &Elf, base: *mut c_void, mapped: &[Range<usize>]) -> Result<*const c_void> {
+    let phoff = elf.header.e_phoff;
+    let vaddr = match elf
+        .program_headers
+        .iter()
+        .find(|h| h.p_type == goblin::elf::program_header::PT_PHDR)
+    {
+        Some(phdr) => phdr.p_vaddr,
+        None => elf
+            .program_headers
+            .iter()
+            .filter(|h| h.p_type == goblin::elf::program_header::PT_LOAD)
+            .find(|h| phoff >= h.p_offset && phoff - h.p_offset < h.p_filesz)
+            .map(|h| h.p_vaddr + (phoff - h.p_offset))
+            .ok_or(LoaderError::BadOffset)?,
+    };
+    let vaddr = to_usize(vaddr)?;
+    check_mapped(mapped, vaddr, elf.program_headers.len() * SIZEOF_PHDR)?;
+    Ok(base.wrapping_add(vaddr))
+}
+
+// Where the segment a program header describes was mapped
+fn mapped_segment(base: *mut c_void, h: &goblin::elf::ProgramHeader) -> Result<Segment> {
+    Ok(Segment {
+        addr: base.wrapping_add(to_usize(h.p_vaddr)?),
+        len: to_usize(h.p_memsz)?,
+        flags: h.p_flags,
+        p_type: h.p_type,
+        offset: to_usize(h.p_offset)?,
+    })
+}
+
+// PT_LOAD headers and the segments they were mapped to
+fn mapped_segments(
+    base: *mut c_void,
+    load_phdrs: &[&goblin::elf::ProgramHeader],
+) -> Result<Vec<Segment>> {
+    load_phdrs.iter().map(|h| mapped_segment(base, h)).collect()
+}
+
+// The first segment of type p_type where it was mapped
+fn find_segment(elf: &Elf, base: *mut c_void, p_type: u32) -> Result<Option<Segment>> {
+    elf.program_headers
+        .iter()
+        .find(|h| h.p_type == p_type)
+        .map(|h| mapped_segment(base, h))
+        .transpose()
+}
+
+// Map an ELF that takes care of its own relocations, constructors and TLS, i.e. the dynamic
+// linker or a program it's about to load. It gets its full program header table.
+fn map_unrelocated(elf: &Elf, raw_file: &[u8], aslr: bool) -> Result<MappedImage> {
+    let load_phdrs: Vec<&goblin::elf::ProgramHeader> = elf
+        .program_headers
+        .iter()
+        .filter(|h| h.p_type == goblin::elf::program_header::PT_LOAD)
+        .collect();
+    let (base, mapped, reservation) = map_segments(&load_phdrs, raw_file, aslr, is_fixed(elf))?;
+    let entry = base.wrapping_add(to_usize(elf.entry)?);
+    let phdr = mapped_phdrs(elf, base, &mapped)?;
+    let segments = mapped_segments(base, &load_phdrs)?;
+    protect_segments(&segments)?;
+    Ok(MappedImage {
+        base,
+        entry,
+        phdr,
+        phnum: elf.program_headers.len(),
+        initializers: vec![],
+        finalizers: vec![],
+        thread_pointer: std::ptr::null_mut(),
+        ifuncs: vec![],
+        segments,
+        symbols: symbols(elf, base)?,
+        exports: defined_symbols(&elf.dynsyms, &elf.dynstrtab, base, true)?,
+        interpreter: None,
+        exec_stack: exec_stack(elf),
+        // The dynamic linker applies it itself
+        relro: None,
+        tls: find_segment(elf, base, PT_TLS)?,
+        dynamic: find_segment(elf, base, PT_DYNAMIC)?,
+        _mappings: vec![reservation],
+    })
+}
+
+// Whether the ELF has to be mapped at the vaddrs it was linked at. ET_EXEC isn't position
+// independent, so it can't be moved and aslr doesn't apply to it.
+fn is_fixed(elf: &Elf) -> bool {
+    elf.header.e_type == ET_EXEC
+}
+
+// Whether the ELF's PT_GNU_STACK marks the stack executable
+fn exec_stack(elf: &Elf) -> bool {
+    elf.program_headers
+        .iter()
+        .find(|h| h.p_type == goblin::elf::program_header::PT_GNU_STACK)
+        .is_some_and(|h| h.p_flags & PF_X != 0)
+}
+
+// The PT_GNU_RELRO range where it was mapped, checking it lies within the image's segments
+fn relro_segment(elf: &Elf, base: *mut c_void, mapped: &[Range<usize>]) -> Result<Option<Segment>> {
+    let Some(h) = elf
+        .program_headers
+        .iter()
+        .find(|h| h.p_type == goblin::elf::program_header::PT_GNU_RELRO)
+    else {
+        return Ok(None);
+    };
+    check_mapped(mapped, to_usize(h.p_vaddr)?, to_usize(h.p_memsz)?)?;
+    Ok(Some(mapped_segment(base, h)?))
+}
+
+// Map the dynamic linker named by PT_INTERP as an image of its own
+fn map_interpreter(path: &str, aslr: bool) -> Result<MappedImage> {
+    let raw_file = std::fs::read(path)?;
+    let elf = Elf::parse(&raw_file)?;
+    check_header(&elf)?;
+    if elf.interpreter.is_some() {
+        return Err(LoaderError::UnsupportedFileType);
+    }
+    map_unrelocated(&elf, &raw_file, aslr)
+}
+
+/// Map the loadable segments of a parsed SHELF into memory.
+/// With `aslr` the image goes at a random address instead of wherever the kernel puts it.
+///
+/// A non-PIE ELF (ET_EXEC) is always mapped at the addresses it was linked at, failing with
+/// [`LoaderError::AddressInUse`] if the loader already has something there.
+///
+/// A dynamically linked ELF (one with PT_INTERP) isn't relocated. Its dynamic linker is mapped
+/// as [`MappedImage::interpreter`] to do that, along with running its constructors and setting
+/// up TLS, the same as when the kernel runs it.
+pub fn map_elf(elf: &Elf, raw_file: &[u8], aslr: bool) -> Result<MappedImage> {
+    map_elf_with(elf, raw_file, aslr, false)
+}
+
+// map_elf, also applying DT_RELR with relr
+fn map_elf_with(elf: &Elf, raw_file: &[u8], aslr: bool, relr: bool) -> Result<MappedImage> {
+    check_header(elf)?;
+    if let Some(interpreter) = elf.interpreter {
+        let mut image = map_unrelocated(elf, raw_file, aslr)?;
+        image.interpreter = Some(Box::new(map_interpreter(interpreter, aslr)?));
+        return Ok(image);
+    }
+
+    let mut load_phdrs: Vec<&goblin::elf::ProgramHeader> = vec![];
+    let mut tls_phdr: Option<&goblin::elf::ProgramHeader> = None;
+
+    // Get relevant headers. We only load TLS and DYNAMIC segment headers into SHELF memory
+    let mut phdrs: Vec<ProgramHeader> = vec![];
+    for h in elf.program_headers.iter() {
+        match h.p_type {
+            goblin::elf::program_header::PT_LOAD => load_phdrs.push(h),
+            goblin::elf::program_header::PT_TLS => {
+                tls_phdr = Some(h);
+                phdrs.push(ProgramHeader::from(h.clone()))
+            }
+            goblin::elf::program_header::PT_DYNAMIC => phdrs.push(ProgramHeader::from(h.clone())),
+            _ => (),
+        }
+    }
+    // Once it's mapped, failing to load unmaps it again along with the TLS block
+    let (base, mapped, reservation) = map_segments(&load_phdrs, raw_file, aslr, is_fixed(elf))?;
+    let mut mappings = vec![reservation];
+    let entry = base.wrapping_add(to_usize(elf.entry)?);
+    debug!("entry: {:?}", entry);
+
+    // Copy in the phdrs over the file's own table, either where the loadable segment holding it
+    // was mapped or, when no segment holds it, at the same offset into the image's first page.
+    // That has to be a page one of the segments was committed to.
+    let phnum = phdrs.len();
+    let phoff = to_usize(elf.header.e_phoff)?;
+    let phdrs_vaddr = match load_phdrs
+        .iter()
+        .find(|h| elf.header.e_phoff >= h.p_offset && elf.header.e_phoff - h.p_offset < h.p_filesz)
+    {
+        Some(h) => to_usize(h.p_vaddr + (elf.header.e_phoff - h.p_offset))?,
+        None => segment_end(mapped[0].start, phoff)?,
+    };
+    check_mapped(&mapped, phdrs_vaddr, phnum * SIZEOF_PHDR)?;
+    let phdrs = unsafe {
+        let dst_phdrs_ptr = base.wrapping_add(phdrs_vaddr);
+        let dst_phdrs = std::slice::from_raw_parts_mut(dst_phdrs_ptr as *mut ProgramHeader, phnum);
+        dst_phdrs.copy_from_slice(&phdrs);
+        dst_phdrs_ptr
+    };
+
+    // Fix up absolute addresses now that we know where the image lives
+    if reloc::has_text_relocations(elf) {
+        debug!("relocating into text segments");
+    }
+    let ifuncs = reloc::relocate(elf, base, &mapped, relr)?;
+    let initializers = init::initializers(elf, base, &mapped)?;
+    let finalizers = init::finalizers(elf, base, &mapped)?;
+    let thread_pointer = match tls_phdr {
+        Some(tls_phdr) => {
+            let (thread_pointer, block) = unsafe { tls::map_tls(tls_phdr, base, &mapped)? };
+            mappings.push(block);
+            thread_pointer
+        }
+        None => std::ptr::null_mut(),
+    };
+
+    // Now that nothing else needs to be written, give each segment its own permissions
+    let segments = mapped_segments(base, &load_phdrs)?;
+    protect_segments(&segments)?;
+
+    Ok(MappedImage {
+        base,
+        entry,
+        phdr: phdrs,
+        phnum,
+        initializers,
+        finalizers,
+        thread_pointer,
+        ifuncs,
+        segments,
+        symbols: symbols(elf, base)?,
+        exports: defined_symbols(&elf.dynsyms, &elf.dynstrtab, base, true)?,
+        interpreter: None,
+        exec_stack: exec_stack(elf),
+        relro: relro_segment(elf, base, &mapped)?,
+        tls: find_segment(elf, base, PT_TLS)?,
+        dynamic: find_segment(elf, base, PT_DYNAMIC)?,
+        _mappings: mappings,
+    })
+}
+
+// Named symbols defined in the image, at their runtime addresses.
+// TLS symbols are left out as their values are offsets into the TLS block rather than addresses.
+fn symbols(elf: &Elf, base: *mut c_void) -> Result<Vec<Symbol>> {
+    if elf.syms.is_empty() {
+        defined_symbols(&elf.dynsyms, &elf.dynstrtab, base, false)
+    } else {
+        defined_symbols(&elf.syms, &elf.strtab, base, false)
+    }
+}
+
+// The named symbols a symbol table defines, optionally only the global and weak ones
+fn defined_symbols(
+    syms: &Symtab,
+    strtab: &Strtab,
+    base: *mut c_void,
+    exported_only: bool,
+) -> Result<Vec<Symbol>> {
+    syms.iter()
+        .filter(|sym| {
+            sym.st_shndx != SHN_UNDEF as usize
+                && !(exported_only && sym.st_bind() == STB_LOCAL)
+                && sym.st_shndx != SHN_ABS as usize
+                && matches!(sym.st_type(), STT_NOTYPE | STT_OBJECT | STT_FUNC)
+        })
+        .filter_map(|sym| match strtab.get_at(sym.st_name) {
+            Some(name) if !name.is_empty() => Some((name, sym)),
+            _ => None,
+        })
+        .map(|(name, sym)| {
+            Ok(Symbol {
+                name: name.into(),
+                addr: base.wrapping_add(to_usize(sym.st_value)?),
+                size: to_usize(sym.st_size)?,
+            })
+        })
+        .collect()
+}
+
+/// Run a SHELF straight from memory with the given arguments, starting with its `argv[0]`.
+/// Only returns if loading fails.
+pub fn load_from_bytes(raw_file: &[u8], args: Vec<String>) -> Result<Infallible> {
+    Loader::from_bytes(raw_file).args(args).exec()
+}
+
+/// Read a SHELF from a file and run it like [`load_from_bytes`]
+pub fn load_from_path(path: impl AsRef<Path>, args: Vec<String>) -> Result<Infallible> {
+    let raw_file = std::fs::read(path)?;
+    load_from_bytes(&raw_file, args)
+}
+
+// Value of the first entry with the given tag in the dynamic section
+fn dynamic_entry(elf: &Elf, tag: u64) -> Option<u64> {
+    elf.dynamic
+        .as_ref()?
+        .dyns
+        .iter()
+        .find(|dyn_entry| dyn_entry.d_tag == tag)
+        .map(|dyn_entry| dyn_entry.d_val)
+}
+
+// End of the range [start, start + len), failing if it overflows
+fn segment_end(start: usize, len: usize) -> Result<usize> {
+    start.checked_add(len).ok_or(LoaderError::BadOffset)
+}
+
+impl<'a> Loader<'a> {
+    pub fn from_bytes(raw_file: &'a [u8]) -> Self {
+        Self::new(Cow::Borrowed(raw_file))
+    }
+
+    /// Like [`from_bytes`](Self::from_bytes), but the Loader owns the buffer so it can
+    /// [`scrub`](Self::scrub) it
+    pub fn from_vec(raw_file: Vec<u8>) -> Loader<'static> {
+        Loader::new(Cow::Owned(raw_file))
+    }
+
+    /// Read the SHELF out of a file descriptor, like a memfd, a pipe or an open file. Regular
+    /// files (memfds included) are read in full whatever their current offset, anything else is
+    /// read until EOF. The fd is only borrowed so it's left open.
+    ///
+    /// A bare fd, e.g. from `memfd_create`, can be passed with
+    /// [`BorrowedFd::borrow_raw`](std::os::fd::BorrowedFd::borrow_raw).
+    pub fn from_fd(fd: impl AsFd) -> Result<Loader<'static>> {
+        // The fd is open for as long as it's borrowed, just don't let dropping the File close it
+        let file = ManuallyDrop::new(unsafe { File::from_raw_fd(fd.as_fd().as_raw_fd()) });
+        let metadata = file.metadata()?;
+        let raw_file = if metadata.file_type().is_file() {
+            let mut raw_file = vec![0; to_usize(metadata.len())?];
+            file.read_exact_at(&mut raw_file, 0)?;
+            raw_file
+        } else {
+            let mut raw_file = vec![];
+            (&*file).read_to_end(&mut raw_file)?;
+            raw_file
+        };
+        Ok(Loader::from_vec(raw_file))
+    }
+
+    fn new(raw_file: Cow<'a, [u8]>) -> Self {
+        Loader {
+            raw_file,
+            args: None,
+            env: None,
+            rewrite_argv: true,
+            run_init: false,
+            reuse_stack: false,
+            aslr: false,
+            relro: false,
+            relr: false,
+            scrub: false,
+            decrypt: None,
+            before_exec: None,
+        }
+    }
+
+    /// Arguments to pass to the SHELF instead of the loader's own, starting with its `argv[0]`
+    pub fn args(mut self, args: Vec<String>) -> Self {
+        self.args = Some(args);
+        self
+    }
+
+    /// Environment to pass to the SHELF instead of the loader's own, as `(name, value)` pairs in
+    /// the order they go in envp. Its strings are copied for the SHELF, whichever stack it gets.
+    pub fn env(mut self, env: Vec<(String, String)>) -> Self {
+        self.env = Some(env);
+        self
+    }
+
+    /// Start the SHELF with an empty environment, the same as `env(vec![])`
+    pub fn clear_env(self) -> Self {
+        self.env(vec![])
+    }
+
+    /// Whether to drop the loader's `argv[0]` so the SHELF sees `argv[1..]` (the default) or pass
+    /// the loader's argv through unchanged. The loader's `argv[0]` is kept if there's nothing after
+    /// it. Has no effect if args are set.
+    pub fn rewrite_argv(mut self, rewrite_argv: bool) -> Self {
+        self.rewrite_argv = rewrite_argv;
+        self
+    }
+
+    /// Whether to run the SHELF's constructors before jumping to its entry point, as a dynamic
+    /// linker would. Off by default because a static libc normally runs them itself during
+    /// startup, so doing both would run every constructor twice.
+    ///
+    /// They're called right before the entry point, after [`on_before_exec`](Self::on_before_exec),
+    /// on the SHELF's stack and with its thread pointer installed.
+    pub fn run_init(mut self, run_init: bool) -> Self {
+        self.run_init = run_init;
+        self
+    }
+
+    /// Whether to hand the SHELF the loader's own initial stack instead of mapping a fresh 8 MiB
+    /// one. Reusing it costs no extra memory, but the SHELF's stack pointer ends up above the
+    /// loader's frames and relies on finding argv by walking the stack.
+    pub fn reuse_stack(mut self, reuse_stack: bool) -> Self {
+        self.reuse_stack = reuse_stack;
+        self
+    }
+
+    /// Whether to load the SHELF at a randomly chosen base anywhere in the address space instead of
+    /// next to the loader's own mappings. Falls back to the kernel's choice if the addresses
+    /// tried are taken. Non-PIE SHELFs always go at their fixed addresses.
+    pub fn aslr(mut self, aslr: bool) -> Self {
+        self.aslr = aslr;
+        self
+    }
+
+    /// Whether to make the SHELF's PT_GNU_RELRO range read-only once it's relocated, before any
+    /// constructors run. Off by default for the same reason as [`relr`](Self::relr), relocating
+    /// again would fault on the read-only range.
+    pub fn relro(mut self, relro: bool) -> Self {
+        self.relro = relro;
+        self
+    }
+
+    /// Whether to apply the SHELF's DT_RELR packed relative relocations. Off by default because
+    /// a static-pie libc relocates itself again during startup. Applying the other relocations a
+    /// second time writes the same values, but each DT_RELR one adds the base to the word again.
+    /// Turn it on for SHELFs that rely on the loader to relocate them.
+    pub fn relr(mut self, relr: bool) -> Self {
+        self.relr = relr;
+        self
+    }
+
+    /// Whether to zero and free the SHELF's file contents right before jumping to it, so only the
+    /// mapped image is left behind. Only buffers handed over with [`from_vec`](Self::from_vec)
+    /// can be scrubbed, a borrowed one is left alone.
+    pub fn scrub(mut self, scrub: bool) -> Self {
+        self.scrub = scrub;
+        self
+    }
+
+    /// Decrypt the SHELF in memory with `scheme` before anything else looks at it
+    pub fn decrypt_with(mut self, scheme: DecryptScheme) -> Self {
+        self.decrypt = Some(scheme);
+        self
+    }
+
+    /// Call `callback` right before jumping to the SHELF, once everything else has been set up.
+    /// Useful for logging where things ended up, stopping in a debugger or tweaking an auxv value
+    /// through [`ExecContext::auxv`]. The callback must return for the SHELF to start.
+    pub fn on_before_exec(mut self, callback: impl FnOnce(&mut ExecContext) + 'a) -> Self {
+        self.before_exec = Some(Box::new(callback));
+        self
+    }
+
+    /// Map and relocate the SHELF without running it. It's decrypted first if
+    /// [`decrypt_with`](Self::decrypt_with) is set. Then with the `gzip` or `zstd` features a SHELF
+    /// compressed with them is decompressed in memory, recognized by its magic bytes. Useful to
+    /// inspect the loaded image.
+    pub fn map(&self) -> Result<MappedImage> {
+        let decrypted = match &self.decrypt {
+            Some(scheme) => Cow::Owned(scheme.decrypt(&self.raw_file)?),
+            None => Cow::Borrowed(&self.raw_file[..]),
+        };
+        let raw_file = unpack::unpack(&decrypted)?;
+        let image = match Object::parse(&raw_file)? {
+            Object::Elf(elf) => map_elf_with(&elf, &raw_file, self.aslr, self.relr)?,
+            _ => return Err(LoaderError::UnsupportedFileType),
+        };
+        if self.relro {
+            image.protect_relro()?;
+        }
+        // Decrypted and decompressed copies are ours to scrub whatever the file was handed over as
+        if self.scrub {
+            if let Cow::Owned(raw_file) = raw_file {
+                scrub_buffer(raw_file);
+            }
+            if let Cow::Owned(decrypted) = decrypted {
+                scrub_buffer(decrypted);
+            }
+        }
+        Ok(image)
+    }
+
+    // Take over the loader's initial stack. The vector gets copied over the original one, with the
+    // strings it points to left where the kernel put them.
+    fn reuse_initial_stack(&self, prot: i32) -> Result<InitialStack> {
+        let stack = unsafe { get_initial_stack() };
+        // PROT_GROWSDOWN extends the change down to the start of the stack mapping, the same way
+        // glibc makes its stack executable
+        let (page, len) = page_align(stack.end() as usize, 1)?;
+        protect(page, len, prot | PROT_GROWSDOWN)?;
+
+        // Leave off the NULL terminators, the vector gets new ones
+        let host_argv = &stack.argv[..stack.argv.len() - 1];
+        let host_envp = &stack.envp[..stack.envp.len() - 1];
+        let argv: Vec<*const c_char> = match &self.args {
+            Some(args) => args
+                .iter()
+                .map(|arg| leak_c_string(arg))
+                .collect::<Result<_>>()?,
+            None => host_args(host_argv, self.rewrite_argv),
+        };
+        let envp: Vec<*const c_char> = match &self.env {
+            Some(env) => env
+                .iter()
+                .map(|(key, value)| leak_c_string(&format!("{}={}", key, value)))
+                .collect::<Result<_>>()?,
+            None => host_envp.to_vec(),
+        };
+        // Good enough without a copy of its own
+        let execfn = argv.first().copied().unwrap_or(std::ptr::null());
+        Ok(InitialStack {
+            end: stack.end(),
+            argv,
+            envp,
+            auxv: stack.auxv.to_vec(),
+            execfn,
+        })
+    }
+
+    // Map a fresh stack and copy the argument and environment strings to its top like the kernel
+    // does. The vector goes right below them.
+    fn fresh_initial_stack(&self, prot: i32) -> Result<InitialStack> {
+        let args: Vec<CString> = match &self.args {
+            Some(args) => args
+                .iter()
+                .map(|arg| c_string(arg.clone().into()))
+                .collect::<Result<_>>()?,
+            None => host_args(&std::env::args_os().collect::<Vec<_>>(), self.rewrite_argv)
+                .into_iter()
+                .map(|arg| c_string(arg.into_vec()))
+                .collect::<Result<_>>()?,
+        };
+        let env: Vec<CString> = match &self.env {
+            Some(env) => env
+                .iter()
+                .map(|(key, value)| c_string(format!("{}={}", key, value).into()))
+                .collect::<Result<_>>()?,
+            None => std::env::vars_os()
+                .map(|(key, value)| {
+                    let mut var = key.into_vec();
+                    var.push(b'=');
+                    var.extend_from_slice(value.as_bytes());
+                    c_string(var)
+                })
+                .collect::<Result<_>>()?,
+        };
+
+        // Like the kernel, only let the strings take up a quarter of the stack
+        let strings_len: usize = args
+            .iter()
+            .chain(env.iter())
+            .map(|s| s.as_bytes_with_nul().len())
+            .sum();
+        let execfn_len = args.first().map_or(0, |arg| arg.as_bytes_with_nul().len());
+        if strings_len + execfn_len > STACK_SIZE / 4 {
+            return Err(LoaderError::StackTooSmall);
+        }
+
+        let mut top = map_stack(STACK_SIZE, prot)?;
+        let (argv, envp, execfn) = unsafe {
+            // Leave a NULL word at the very top as the end marker
+            top = top.sub(std::mem::size_of::<usize>());
+            // The kernel puts its own copy of the path right below it
+            let execfn = match args.first() {
+                Some(arg) => push_string(&mut top, arg),
+                None => std::ptr::null(),
+            };
+            // Push in reverse so the strings end up in order
+            let mut envp: Vec<*const c_char> = env
+                .iter()
+                .rev()
+                .map(|var| push_string(&mut top, var))
+                .collect();
+            let mut argv: Vec<*const c_char> = args
+                .iter()
+                .rev()
+                .map(|arg| push_string(&mut top, arg))
+                .collect();
+            argv.reverse();
+            envp.reverse();
+            (argv, envp, execfn)
+        };
+        let end = (top as usize & !15) as *mut usize;
+        Ok(InitialStack {
+            end,
+            argv,
+            envp,
+            auxv: build_auxv(),
+            execfn,
+        })
+    }
+
+    /// Map the SHELF and jump to it with a stack of its own, or the loader's if
+    /// [`reuse_stack`](Self::reuse_stack) is set. Only returns if loading fails.
+    pub fn exec(self) -> Result<Infallible> {
+        let image = self.map()?;
+        // A dynamic linker takes it from here and jumps to the SHELF's entry itself
+        let entry = image
+            .interpreter
+            .as_ref()
+            .map_or(image.entry, |interpreter| interpreter.entry);
+        unsafe { self.exec_at(image, entry) }
+    }
+
+    /// Run the SHELF in a forked child like [`exec`](Self::exec) and wait for it to finish,
+    /// returning how it exited or was killed. Loading happens in the child too so the loader's
+    /// own process is left untouched; if it fails or panics there the error comes back as
+    /// [`LoaderError::ChildFailed`].
+    ///
+    /// As with any fork, only the calling thread carries on in the child, so avoid this while
+    /// other threads may be holding locks the loader needs, like the allocator's.
+    pub fn spawn(mut self) -> Result<ExitStatus> {
+        // The child reports a failed load through the pipe. It's closed right before the jump so
+        // the SHELF doesn't inherit it and the parent sees EOF either way.
+        let mut fds = [0; 2];
+        if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } != 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        let [read_fd, write_fd] = fds;
+        let pid = unsafe { libc::fork() };
+        if pid < 0 {
+            let err = std::io::Error::last_os_error();
+            unsafe {
+                libc::close(read_fd);
+                libc::close(write_fd);
+            }
+            return Err(err.into());
+        }
+
+        if pid == 0 {
+            unsafe { libc::close(read_fd) };
+            let callback = self.before_exec.take();
+            self.before_exec = Some(Box::new(move |context| {
+                if let Some(callback) = callback {
+                    callback(context);
+                }
+                unsafe { libc::close(write_fd) };
+            }));
+            // Unwinding out of here would return from spawn a second time, in the child
+            let msg = match std::panic::catch_unwind(AssertUnwindSafe(|| self.exec())) {
+                Ok(Err(err)) => err.to_string(),
+                Err(panic) => match panic
+                    .downcast_ref::<&str>()
+                    .copied()
+                    .or_else(|| panic.downcast_ref::<String>().map(String::as_str))
+                {
+                    Some(panic) => format!("panicked: {}", panic),
+                    None => "panicked".into(),
+                },
+            };
+            unsafe {
+                libc::write(write_fd, msg.as_ptr() as *const c_void, msg.len());
+                libc::_exit(127)
+            }
+        }
+
+        unsafe { libc::close(write_fd) };
+        let mut msg = String::new();
+        // A read error just means there's no message to report
+        let _ = unsafe { File::from_raw_fd(read_fd) }.read_to_string(&mut msg);
+        let mut status = 0;
+        while unsafe { libc::waitpid(pid, &mut status, 0) } < 0 {
+            let err = std::io::Error::last_os_error();
+            if err.kind() != std::io::ErrorKind::Interrupted {
+                return Err(err.into());
+            }
+        }
+        if !msg.is_empty() {
+            return Err(LoaderError::ChildFailed(msg));
+        }
+        Ok(ExitStatus::from_raw(status))
+    }
+
+    /// Like [`exec`](Self::exec), but run an image from [`map`](Self::map) starting at `addr`
+    /// instead of its entry point, e.g. an exported function found with [`MappedImage::symbol`].
+    ///
+    /// `addr` is jumped to the way the entry point is, with the stack pointer at argc and no
+    /// return address, so it must not return. Only returns if setting up the stack fails.
+    ///
+    /// # Safety
+    ///
+    /// `addr` must point at code in `image` that can run from there, or elsewhere that's mapped and
+    /// executable.
+    pub unsafe fn exec_at(self, image: MappedImage, addr: *const c_void) -> Result<Infallible> {
+        let mut stack = if self.reuse_stack {
+            self.reuse_initial_stack(stack_prot(image.exec_stack))?
+        } else {
+            self.fresh_initial_stack(stack_prot(image.exec_stack))?
+        };
+        setup_auxv(&mut stack.auxv, &image, stack.execfn)?;
+        let mut vector = build_stack_vector(&stack.argv, &stack.envp, &stack.auxv);
+
+        let relro = self.relro;
+        info!("Starting SHELF...");
+        // Everything has been read out of the file by now
+        if let (true, Cow::Owned(raw_file)) = (self.scrub, self.raw_file) {
+            scrub_buffer(raw_file);
+        }
+        if let Some(callback) = self.before_exec {
+            callback(&mut ExecContext {
+                entry: addr,
+                base: image.base,
+                stack_pointer: initial_sp(stack.end, vector.len()),
+                vector: &vector,
+                auxv: &mut stack.auxv,
+                thread_pointer: image.thread_pointer,
+            });
+            // Same length, so the stack pointer it was shown still holds
+            vector = build_stack_vector(&stack.argv, &stack.envp, &stack.auxv);
+        }
+        unsafe { image.resolve_ifuncs(relro)? };
+        // Called by exec_shelf once the SHELF's thread pointer is installed
+        let initializers: &[*const c_void] = if self.run_init {
+            &image.initializers
+        } else {
+            &[]
+        };
+        unsafe { exec_shelf(addr, stack.end, &vector, image.thread_pointer, initializers) }
+    }
+}
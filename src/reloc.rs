@@ -0,0 +1,203 @@
+//! Applying a mapped SHELF's dynamic relocations
+
+use crate::tls::{self, TLS_MODULE};
+use crate::{check_mapped, dynamic_entry, to_usize, LoaderError, Result};
+use goblin::elf::dynamic::{DF_TEXTREL, DT_FLAGS, DT_TEXTREL};
+use goblin::elf::program_header::PT_TLS;
+#[cfg(target_arch = "x86")]
+use goblin::elf::reloc::{
+    R_386_GLOB_DAT as R_GLOB_DAT, R_386_IRELATIVE as R_IRELATIVE, R_386_JMP_SLOT as R_JUMP_SLOT,
+    R_386_NONE as R_NONE, R_386_RELATIVE as R_RELATIVE, R_386_TLS_DTPMOD32 as R_DTPMOD,
+    R_386_TLS_DTPOFF32 as R_DTPOFF, R_386_TLS_TPOFF as R_TPOFF,
+};
+#[cfg(target_arch = "aarch64")]
+use goblin::elf::reloc::{
+    R_AARCH64_GLOB_DAT as R_GLOB_DAT, R_AARCH64_IRELATIVE as R_IRELATIVE,
+    R_AARCH64_JUMP_SLOT as R_JUMP_SLOT, R_AARCH64_NONE as R_NONE, R_AARCH64_RELATIVE as R_RELATIVE,
+    R_AARCH64_TLS_DTPMOD as R_DTPMOD, R_AARCH64_TLS_DTPREL as R_DTPOFF,
+    R_AARCH64_TLS_TPREL as R_TPOFF,
+};
+#[cfg(target_arch = "x86_64")]
+use goblin::elf::reloc::{
+    R_X86_64_DTPMOD64 as R_DTPMOD, R_X86_64_DTPOFF64 as R_DTPOFF, R_X86_64_GLOB_DAT as R_GLOB_DAT,
+    R_X86_64_IRELATIVE as R_IRELATIVE, R_X86_64_JUMP_SLOT as R_JUMP_SLOT, R_X86_64_NONE as R_NONE,
+    R_X86_64_RELATIVE as R_RELATIVE, R_X86_64_TPOFF64 as R_TPOFF,
+};
+use goblin::elf::section_header::SHN_UNDEF;
+use goblin::elf::sym::STB_WEAK;
+use goblin::elf::Elf;
+use libc::c_void;
+use std::ops::Range;
+
+// Packed relative relocations, newer than goblin
+const DT_RELRSZ: u64 = 35;
+const DT_RELR: u64 = 36;
+
+// An IFUNC resolver, given AT_HWCAP and returning the implementation to use
+type Resolver = extern "C" fn(usize) -> usize;
+
+// Address of the word at vaddr in the image, checking it lies within the mapped segments
+pub(crate) fn slot(base: *mut c_void, mapped: &[Range<usize>], vaddr: u64) -> Result<*mut usize> {
+    let vaddr = to_usize(vaddr)?;
+    check_mapped(mapped, vaddr, std::mem::size_of::<usize>())?;
+    Ok(base.wrapping_add(vaddr) as *mut usize)
+}
+
+// Apply the DT_RELR table. Each even entry is the vaddr of a word to relocate, and each odd entry
+// a bitmap of which of the words following the last one relocated need it too. Relocating adds
+// the base to whatever the word holds.
+fn relocate_relr(elf: &Elf, base: *mut c_void, mapped: &[Range<usize>]) -> Result<()> {
+    let (Some(table), Some(size)) = (dynamic_entry(elf, DT_RELR), dynamic_entry(elf, DT_RELRSZ))
+    else {
+        return Ok(());
+    };
+    let word = std::mem::size_of::<usize>() as u64;
+    let bits = word * 8 - 1;
+    let mut next = 0;
+    for i in 0..size / word {
+        let entry = unsafe { *slot(base, mapped, table.wrapping_add(i * word))? } as u64;
+        if entry & 1 == 0 {
+            let target = slot(base, mapped, entry)?;
+            unsafe { *target = (*target).wrapping_add(base as usize) };
+            next = entry.wrapping_add(word);
+        } else {
+            for bit in 0..bits {
+                if entry >> (bit + 1) & 1 != 0 {
+                    let target = slot(base, mapped, next.wrapping_add(bit * word))?;
+                    unsafe { *target = (*target).wrapping_add(base as usize) };
+                }
+            }
+            next = next.wrapping_add(bits * word);
+        }
+    }
+    Ok(())
+}
+
+// Runtime address of the dynamic symbol at index r_sym.
+// A SHELF has nothing to link against so only symbols defined in the image itself resolve.
+// Undefined weak symbols resolve to 0 as usual.
+fn resolve_symbol(elf: &Elf, base: *mut c_void, r_sym: usize) -> Result<usize> {
+    let sym = elf.dynsyms.get(r_sym).ok_or(LoaderError::BadOffset)?;
+    if sym.st_shndx == SHN_UNDEF as usize {
+        let name = elf.dynstrtab.get_at(sym.st_name).unwrap_or("<unknown>");
+        // The one function a SHELF may expect from its runtime
+        if name == "__tls_get_addr" {
+            return Ok(tls::tls_get_addr as *const () as usize);
+        }
+        if sym.st_bind() == STB_WEAK {
+            return Ok(0);
+        }
+        return Err(LoaderError::UnresolvedSymbol(name.into()));
+    }
+    Ok((base as usize).wrapping_add(to_usize(sym.st_value)?))
+}
+
+// Offset of a TLS symbol within the SHELF's TLS block, 0 for r_sym 0 as used by the local dynamic
+// model. TLS symbols can't come from anywhere else.
+fn tls_symbol_offset(elf: &Elf, r_sym: usize) -> Result<usize> {
+    if r_sym == 0 {
+        return Ok(0);
+    }
+    let sym = elf.dynsyms.get(r_sym).ok_or(LoaderError::BadOffset)?;
+    if sym.st_shndx == SHN_UNDEF as usize {
+        let name = elf.dynstrtab.get_at(sym.st_name).unwrap_or("<unknown>");
+        return Err(LoaderError::UnresolvedSymbol(name.into()));
+    }
+    to_usize(sym.st_value)
+}
+
+/// Whether the ELF has relocations into read-only segments, flagged by DT_TEXTREL or DF_TEXTREL.
+/// They need no special handling as the image stays writable until it's been relocated.
+pub(crate) fn has_text_relocations(elf: &Elf) -> bool {
+    dynamic_entry(elf, DT_TEXTREL).is_some()
+        || dynamic_entry(elf, DT_FLAGS).is_some_and(|flags| flags & DF_TEXTREL != 0)
+}
+
+/// Apply the relocations from DT_RELA, DT_REL and DT_JMPREL. goblin has already found them through
+/// DT_RELA/DT_RELASZ/DT_RELAENT, DT_REL/DT_RELSZ/DT_RELENT and DT_JMPREL/DT_PLTRELSZ in the dynamic
+/// section. PLT slots are filled in eagerly as there is no lazy binding.
+///
+/// With `relr` the DT_RELR table is applied first, see [`Loader::relr`](crate::Loader::relr).
+///
+/// TLS relocations all refer to the SHELF's own block, module [`TLS_MODULE`] for
+/// `__tls_get_addr`, which resolves to the loader's [`tls::tls_get_addr`].
+///
+/// IRELATIVE relocations are returned as the slots and resolvers to fill them with, for
+/// [`resolve_ifuncs`] once everything else is done. Calling them means running SHELF code.
+///
+/// `mapped` are the vaddr ranges committed to the loadable segments, all still writable. Anything
+/// a relocation points outside them is rejected.
+pub(crate) fn relocate(
+    elf: &Elf,
+    base: *mut c_void,
+    mapped: &[Range<usize>],
+    relr: bool,
+) -> Result<Vec<(*mut usize, *const c_void)>> {
+    if relr {
+        relocate_relr(elf, base, mapped)?;
+    }
+    let tls_phdr = elf.program_headers.iter().find(|h| h.p_type == PT_TLS);
+    let mut ifuncs: Vec<(*mut usize, *const c_void)> = vec![];
+    let relocs = elf.dynrelas.iter().chain(elf.dynrels.iter());
+    for reloc in relocs.chain(elf.pltrelocs.iter()) {
+        match reloc.r_type {
+            R_NONE => (),
+            R_RELATIVE | R_IRELATIVE => {
+                let target = slot(base, mapped, reloc.r_offset)?;
+                // REL relocations (i386) keep the addend in the slot itself
+                let addend = match reloc.r_addend {
+                    Some(addend) => addend as usize,
+                    None => unsafe { *target },
+                };
+                let value = (base as usize).wrapping_add(addend);
+                if reloc.r_type == R_IRELATIVE {
+                    // Checked against the segments like any other address the image gives us
+                    check_mapped(mapped, addend, 1)?;
+                    ifuncs.push((target, value as *const c_void));
+                } else {
+                    unsafe { *target = value };
+                }
+            }
+            R_GLOB_DAT | R_JUMP_SLOT => {
+                let target = slot(base, mapped, reloc.r_offset)?;
+                let value = resolve_symbol(elf, base, reloc.r_sym)?;
+                unsafe { *target = value };
+            }
+            R_DTPMOD => {
+                let target = slot(base, mapped, reloc.r_offset)?;
+                unsafe { *target = TLS_MODULE };
+            }
+            R_DTPOFF | R_TPOFF => {
+                let target = slot(base, mapped, reloc.r_offset)?;
+                let addend = match reloc.r_addend {
+                    Some(addend) => addend as usize,
+                    None => unsafe { *target },
+                };
+                let mut value = tls_symbol_offset(elf, reloc.r_sym)?.wrapping_add(addend);
+                // Static TLS is addressed from the thread pointer rather than the block
+                if reloc.r_type == R_TPOFF {
+                    let tls_phdr = tls_phdr.ok_or(LoaderError::BadOffset)?;
+                    value = value.wrapping_add_signed(tls::block_offset(tls_phdr)?);
+                }
+                unsafe { *target = value };
+            }
+            r_type => return Err(LoaderError::UnsupportedRelocation(r_type)),
+        }
+    }
+
+    Ok(ifuncs)
+}
+
+/// Fill each IRELATIVE slot with what its resolver returns, given AT_HWCAP, in order like a
+/// dynamic linker does as resolvers may rely on the slots before them
+///
+/// # Safety
+///
+/// The resolvers are SHELF code, free to do anything, and the slots must be writable.
+pub(crate) unsafe fn resolve_ifuncs(ifuncs: &[(*mut usize, *const c_void)]) {
+    let hwcap = libc::getauxval(libc::AT_HWCAP) as usize;
+    for &(target, resolver) in ifuncs {
+        let resolver: Resolver = std::mem::transmute(resolver);
+        *target = resolver(hwcap);
+    }
+}
@@ -0,0 +1,260 @@
+//! Static TLS for the SHELF's main thread, and `__tls_get_addr` for dynamic TLS accesses to it
+
+use crate::{check_mapped, segment_end, to_usize, LoaderError, OwnedMapping, Result};
+use core::arch::asm;
+use libc::{c_void, mmap, MAP_ANONYMOUS, MAP_FAILED, MAP_PRIVATE, PROT_READ, PROT_WRITE};
+use std::ops::Range;
+
+/// The module ID of the SHELF's TLS block, the only one there is
+pub(crate) const TLS_MODULE: usize = 1;
+
+/// Space reserved for the thread control block at the thread pointer. Big enough for the fields
+/// libcs and compilers read at fixed offsets from %fs, like the stack canary at %fs:0x28.
+#[cfg(any(target_arch = "x86_64", target_arch = "x86"))]
+const TCB_SIZE: usize = 0x100;
+
+/// The aarch64 ABI reserves two words for the TCB at the thread pointer
+#[cfg(target_arch = "aarch64")]
+const TCB_SIZE: usize = 16;
+
+// Work out where everything goes relative to the thread pointer, matching the offsets the linker
+// assumed for local-exec accesses. Returns how much memory is needed below and above the thread
+// pointer and the offset of the TLS block from it.
+//
+// x86-64 and i386 use variant II: the TLS block sits directly below the thread pointer and the
+// TCB starts at it. Padding goes in front of the block so the image keeps its alignment relative
+// to p_vaddr while the thread pointer stays aligned.
+#[cfg(any(target_arch = "x86_64", target_arch = "x86"))]
+fn layout(tls_vaddr: usize, tls_memsz: usize, align: usize) -> Result<(usize, usize, isize)> {
+    let padding = tls_vaddr.wrapping_neg().wrapping_sub(tls_memsz) & (align - 1);
+    let below = segment_end(tls_memsz, padding)?;
+    let offset = isize::try_from(below).map_err(|_| LoaderError::BadOffset)?;
+    Ok((below, TCB_SIZE, -offset))
+}
+
+// aarch64 uses variant I: the TCB sits at the thread pointer and the TLS block follows it,
+// padded to keep its alignment relative to p_vaddr.
+#[cfg(target_arch = "aarch64")]
+fn layout(tls_vaddr: usize, tls_memsz: usize, align: usize) -> Result<(usize, usize, isize)> {
+    let offset = TCB_SIZE + (tls_vaddr.wrapping_sub(TCB_SIZE) & (align - 1));
+    let above = segment_end(offset, tls_memsz)?;
+    Ok((0, above, offset as isize))
+}
+
+/// Offset of the SHELF's TLS block from the thread pointer, for TPOFF relocations
+pub(crate) fn block_offset(tls_phdr: &goblin::elf::ProgramHeader) -> Result<isize> {
+    let align = to_usize(tls_phdr.p_align)?.max(1);
+    if !align.is_power_of_two() {
+        return Err(LoaderError::BadOffset);
+    }
+    let (_, _, offset) = layout(
+        to_usize(tls_phdr.p_vaddr)?,
+        to_usize(tls_phdr.p_memsz)?,
+        align,
+    )?;
+    Ok(offset)
+}
+
+/// Allocate and fill in the TLS block described by a PT_TLS header, returning the thread pointer
+/// to install (%fs on x86-64, TPIDR_EL0 on aarch64, %gs on i386).
+///
+/// The block is laid out the way the architecture's TLS ABI expects, with the initial image
+/// copied out of the mapped SHELF so it must already be relocated. On x86 the first word of the
+/// TCB points to itself.
+///
+/// The TCB also gets a DTV where libcs keep theirs (the second word on x86, the first on aarch64)
+/// for the loader's `__tls_get_addr` to find the block through. It's the loader's own minimal
+/// layout: the number of modules, then the block of each.
+///
+/// The thread pointer isn't installed here as the loader's own TLS would go with it.
+/// [`exec_shelf`](crate::exec_shelf) installs it right before jumping to the SHELF. The block is
+/// never freed.
+///
+/// # Safety
+///
+/// `base` must be the base of a mapped image whose segments cover the vaddrs in `mapped`.
+pub unsafe fn setup_tls(
+    tls_phdr: &goblin::elf::ProgramHeader,
+    base: *mut c_void,
+    mapped: &[Range<usize>],
+) -> Result<*mut c_void> {
+    let (thread_pointer, block) = map_tls(tls_phdr, base, mapped)?;
+    std::mem::forget(block);
+    Ok(thread_pointer)
+}
+
+// setup_tls, also handing back the block's mapping for the image to unmap with it
+pub(crate) unsafe fn map_tls(
+    tls_phdr: &goblin::elf::ProgramHeader,
+    base: *mut c_void,
+    mapped: &[Range<usize>],
+) -> Result<(*mut c_void, OwnedMapping)> {
+    let tls_vaddr = to_usize(tls_phdr.p_vaddr)?;
+    let tls_filesz = to_usize(tls_phdr.p_filesz)?;
+    let tls_memsz = to_usize(tls_phdr.p_memsz)?;
+    let align = to_usize(tls_phdr.p_align)?.max(1);
+    // The thread pointer also needs to be suitably aligned for the TCB
+    let tp_align = align.max(16);
+    if !align.is_power_of_two() || tls_filesz > tls_memsz {
+        return Err(LoaderError::BadOffset);
+    }
+    check_mapped(mapped, tls_vaddr, tls_filesz)?;
+
+    let (below, above, block_offset) = layout(tls_vaddr, tls_memsz, align)?;
+    let alloc_len = below
+        .checked_add(above)
+        .and_then(|len| len.checked_add(tp_align))
+        .ok_or(LoaderError::BadOffset)?;
+    let alloc = mmap(
+        std::ptr::null_mut(),
+        alloc_len,
+        PROT_READ | PROT_WRITE,
+        MAP_PRIVATE | MAP_ANONYMOUS,
+        -1,
+        0,
+    );
+    if alloc == MAP_FAILED {
+        return Err(LoaderError::Mmap(std::io::Error::last_os_error()));
+    }
+    let block_mapping = OwnedMapping {
+        addr: alloc,
+        len: alloc_len,
+    };
+
+    let tp = (alloc as usize + below + tp_align - 1) & !(tp_align - 1);
+    let block = tp.wrapping_add_signed(block_offset) as *mut u8;
+    // .tdata then zeroed .tbss
+    let image = base.wrapping_add(tls_vaddr) as *const u8;
+    std::ptr::copy_nonoverlapping(image, block, tls_filesz);
+    std::ptr::write_bytes(block.add(tls_filesz), 0, tls_memsz - tls_filesz);
+
+    // Leaked for the SHELF to keep, like the block itself
+    let dtv = Box::leak(Box::new([TLS_MODULE, block as usize])).as_ptr() as usize;
+
+    let tcb = tp as *mut usize;
+    std::ptr::write_bytes(tcb, 0, TCB_SIZE / std::mem::size_of::<usize>());
+    // %fs:0 is the TCB's pointer to itself. glibc keeps another copy two words in
+    #[cfg(any(target_arch = "x86_64", target_arch = "x86"))]
+    {
+        *tcb = tp;
+        *tcb.add(1) = dtv;
+        *tcb.add(2) = tp;
+    }
+    #[cfg(target_arch = "aarch64")]
+    {
+        *tcb = dtv;
+    }
+    Ok((tp as *mut c_void, block_mapping))
+}
+
+/// Allocate a GDT entry based at the thread pointer and return its segment selector for %gs
+#[cfg(target_arch = "x86")]
+pub(crate) fn set_thread_area(thread_pointer: *mut c_void) -> Option<u32> {
+    #[repr(C)]
+    struct UserDesc {
+        entry_number: u32,
+        base_addr: u32,
+        limit: u32,
+        flags: u32,
+    }
+    let mut desc = UserDesc {
+        // Let the kernel pick a free entry
+        entry_number: u32::MAX,
+        base_addr: thread_pointer as u32,
+        limit: 0xfffff,
+        // seg_32bit | limit_in_pages | useable
+        flags: 1 | 1 << 4 | 1 << 6,
+    };
+    let ret = unsafe { libc::syscall(libc::SYS_set_thread_area, &mut desc as *mut UserDesc) };
+    if ret != 0 {
+        return None;
+    }
+    Some(desc.entry_number << 3 | 3)
+}
+
+/// The argument to `__tls_get_addr`, filled in by DTPMOD and DTPOFF relocations
+#[repr(C)]
+pub(crate) struct TlsIndex {
+    module: usize,
+    offset: usize,
+}
+
+// The DTV setup_tls left in the current thread's TCB
+#[cfg(target_arch = "x86_64")]
+unsafe fn current_dtv() -> *const usize {
+    let dtv: *const usize;
+    asm!("mov {}, fs:8", out(reg) dtv, options(nostack, readonly, preserves_flags));
+    dtv
+}
+
+#[cfg(target_arch = "x86")]
+unsafe fn current_dtv() -> *const usize {
+    let dtv: *const usize;
+    asm!("mov {}, gs:4", out(reg) dtv, options(nostack, readonly, preserves_flags));
+    dtv
+}
+
+#[cfg(target_arch = "aarch64")]
+unsafe fn current_dtv() -> *const usize {
+    let tp: *const *const usize;
+    asm!("mrs {}, tpidr_el0", out(reg) tp, options(nostack, nomem, preserves_flags));
+    *tp
+}
+
+// Write msg to stderr and kill the process with an illegal instruction, with nothing but a raw
+// syscall as it runs on the SHELF's thread pointer
+#[cfg(target_arch = "x86_64")]
+unsafe fn die(msg: &[u8]) -> ! {
+    asm!(
+        "syscall",
+        "ud2",
+        in("rax") libc::SYS_write,
+        in("rdi") 2,
+        in("rsi") msg.as_ptr(),
+        in("rdx") msg.len(),
+        options(noreturn, nostack),
+    )
+}
+
+#[cfg(target_arch = "x86")]
+unsafe fn die(msg: &[u8]) -> ! {
+    // LLVM reserves ebx so the fd is loaded by hand, it's never needed again
+    asm!(
+        "mov ebx, 2",
+        "int 0x80",
+        "ud2",
+        in("eax") libc::SYS_write,
+        in("ecx") msg.as_ptr(),
+        in("edx") msg.len(),
+        options(noreturn, nostack),
+    )
+}
+
+#[cfg(target_arch = "aarch64")]
+unsafe fn die(msg: &[u8]) -> ! {
+    asm!(
+        "svc 0",
+        "brk #0",
+        in("x8") libc::SYS_write,
+        in("x0") 2,
+        in("x1") msg.as_ptr(),
+        in("x2") msg.len(),
+        options(noreturn, nostack),
+    )
+}
+
+/// `__tls_get_addr` for SHELFs using the general or local dynamic TLS models, which the loader
+/// resolves their references to. It runs on the SHELF's thread pointer so it sticks to raw
+/// pointers and syscalls made with inline asm: Rust's panic machinery and even libc's errno would
+/// use the loader's own TLS.
+///
+/// An index into a module other than the SHELF's kills the process with an illegal instruction
+/// rather than return a bogus address.
+pub(crate) unsafe extern "C" fn tls_get_addr(index: *const TlsIndex) -> *mut c_void {
+    let dtv = current_dtv();
+    let module = (*index).module;
+    if dtv.is_null() || module == 0 || module > *dtv {
+        die(b"shelf-loader-poc: __tls_get_addr called for an unknown TLS module\n");
+    }
+    (*dtv.add(module) as *mut u8).wrapping_add((*index).offset) as *mut c_void
+}
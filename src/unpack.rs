@@ -0,0 +1,32 @@
+//! Unwrapping SHELFs that were compressed before being handed to the loader
+
+#[cfg(any(feature = "gzip", feature = "zstd"))]
+use crate::LoaderError;
+use crate::Result;
+use std::borrow::Cow;
+#[cfg(feature = "gzip")]
+use std::io::Read;
+
+#[cfg(feature = "gzip")]
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+#[cfg(feature = "zstd")]
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+
+/// Decompress the file in memory if it starts with the magic of a format the loader was built to
+/// handle. Anything else is passed through untouched.
+pub(crate) fn unpack(raw_file: &[u8]) -> Result<Cow<'_, [u8]>> {
+    #[cfg(feature = "gzip")]
+    if raw_file.starts_with(GZIP_MAGIC) {
+        let mut unpacked = vec![];
+        flate2::read::GzDecoder::new(raw_file)
+            .read_to_end(&mut unpacked)
+            .map_err(LoaderError::Decompress)?;
+        return Ok(Cow::Owned(unpacked));
+    }
+    #[cfg(feature = "zstd")]
+    if raw_file.starts_with(ZSTD_MAGIC) {
+        let unpacked = zstd::stream::decode_all(raw_file).map_err(LoaderError::Decompress)?;
+        return Ok(Cow::Owned(unpacked));
+    }
+    Ok(Cow::Borrowed(raw_file))
+}
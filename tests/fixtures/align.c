@@ -0,0 +1,9 @@
+/* Exits with 42 plus how far the stack pointer is off 16 byte alignment at _start */
+__attribute__((naked)) void _start(void)
+{
+    __asm__("mov %rsp, %rdi\n"
+            "and $15, %edi\n"
+            "add $42, %edi\n"
+            "mov $60, %eax\n"
+            "syscall");
+}
@@ -0,0 +1,7 @@
+/* Exits with argc, read straight off the initial stack */
+__attribute__((naked)) void _start(void)
+{
+    __asm__("mov (%rsp), %rdi\n"
+            "mov $60, %eax\n"
+            "syscall");
+}
@@ -0,0 +1,22 @@
+#include "shelf.h"
+
+/* Exits with the value of AT_FLAGS from the auxv past argv and envp */
+#define AT_NULL 0
+#define AT_FLAGS 8
+
+__attribute__((used)) static void start(long *sp)
+{
+    long *envp = sp + sp[0] + 2;
+    while (*envp)
+        envp++;
+    for (long *auxv = envp + 1; auxv[0] != AT_NULL; auxv += 2)
+        if (auxv[0] == AT_FLAGS)
+            sys_exit((int)auxv[1]);
+    sys_exit(1);
+}
+
+__attribute__((naked)) void _start(void)
+{
+    __asm__("mov %rsp, %rdi\n"
+            "call start");
+}
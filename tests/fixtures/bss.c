@@ -0,0 +1,13 @@
+#include "shelf.h"
+
+/* Big enough to spill past the file-backed pages */
+static volatile char bss[1 << 16];
+
+void _start(void)
+{
+    for (unsigned long i = 0; i < sizeof(bss); i++)
+        if (bss[i])
+            sys_exit(1);
+    bss[100] = 42;
+    sys_exit(bss[100]);
+}
@@ -0,0 +1,23 @@
+#include "shelf.h"
+
+/* Exits with the number of envp entries, or 255 if no auxv follows them */
+#define AT_NULL 0
+
+__attribute__((used)) static void start(long *sp)
+{
+    long *envp = sp + sp[0] + 2;
+    int envc = 0;
+    while (envp[envc])
+        envc++;
+    long *auxv = envp + envc + 1;
+    for (int i = 0; i < 64; i++, auxv += 2)
+        if (auxv[0] == AT_NULL)
+            sys_exit(envc);
+    sys_exit(255);
+}
+
+__attribute__((naked)) void _start(void)
+{
+    __asm__("mov %rsp, %rdi\n"
+            "call start");
+}
@@ -0,0 +1,6 @@
+#include "shelf.h"
+
+void _start(void)
+{
+    sys_exit(42);
+}
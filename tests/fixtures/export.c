@@ -0,0 +1,12 @@
+#include "shelf.h"
+
+/* Exports run() for the loader to jump to in place of the entry point */
+__attribute__((noreturn)) void run(void)
+{
+    sys_exit(33);
+}
+
+void _start(void)
+{
+    sys_exit(1);
+}
@@ -0,0 +1,8 @@
+/* Dynamically linked against the system libc, unlike the other fixtures */
+#include <stdio.h>
+
+int main(int argc, char **argv)
+{
+    printf("hello from %s\n", argv[0]);
+    return 40 + argc;
+}
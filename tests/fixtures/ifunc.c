@@ -0,0 +1,18 @@
+#include "shelf.h"
+
+static int impl(void)
+{
+    return 42;
+}
+
+static void *resolve(void)
+{
+    return impl;
+}
+
+int answer(void) __attribute__((ifunc("resolve")));
+
+void _start(void)
+{
+    sys_exit(answer());
+}
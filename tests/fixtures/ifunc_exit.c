@@ -0,0 +1,14 @@
+#include "shelf.h"
+
+/* A resolver with a side effect, which only running the SHELF may trigger */
+static void *resolve(void)
+{
+    sys_exit(77);
+}
+
+int answer(void) __attribute__((ifunc("resolve")));
+
+void _start(void)
+{
+    sys_exit(answer());
+}
@@ -0,0 +1,27 @@
+#include "shelf.h"
+
+/* Run by the loader before _start: the preinit array, then the constructors by priority */
+static int order;
+__thread int tdata = 1;
+
+static void preinit(void)
+{
+    order = 3;
+}
+__attribute__((section(".preinit_array"), used)) static void (*preinit_fn)(void) = preinit;
+
+__attribute__((constructor(101))) static void first(void)
+{
+    order = order * 10 + 1;
+}
+
+__attribute__((constructor(102))) static void second(void)
+{
+    order = order * 10 + 2;
+    tdata = 40;
+}
+
+void _start(void)
+{
+    sys_exit(order == 312 ? tdata + 2 : order);
+}
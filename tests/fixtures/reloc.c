@@ -0,0 +1,13 @@
+#include "shelf.h"
+
+static int values[] = {10, 12, 20};
+/* Each of these needs a relative relocation */
+int *volatile pointers[] = {&values[0], &values[1], &values[2]};
+
+void _start(void)
+{
+    int sum = 0;
+    for (int i = 0; i < 3; i++)
+        sum += *pointers[i];
+    sys_exit(sum);
+}
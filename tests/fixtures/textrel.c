@@ -0,0 +1,15 @@
+#include "shelf.h"
+
+__attribute__((visibility("hidden"))) int answer = 42;
+
+/* An absolute pointer in .text, so it takes a relocation in a read-only executable segment */
+__asm__(".section .text\n"
+        ".balign 8\n"
+        "answer_ptr: .quad answer\n"
+        ".previous");
+extern int *const answer_ptr;
+
+void _start(void)
+{
+    sys_exit(*answer_ptr);
+}
@@ -0,0 +1,10 @@
+#include "shelf.h"
+
+__thread int tdata = 40;
+__thread long tbss[64];
+
+void _start(void)
+{
+    tbss[3] += 2;
+    sys_exit(tdata + (int)tbss[3] + (int)tbss[10]);
+}
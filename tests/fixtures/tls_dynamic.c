@@ -0,0 +1,11 @@
+#include "shelf.h"
+
+/* Built as a shared object these go through __tls_get_addr instead of %fs offsets */
+__thread int tdata = 40;
+__thread long tbss[4];
+
+void _start(void)
+{
+    tbss[1] += 2;
+    sys_exit(tdata + (int)tbss[1]);
+}
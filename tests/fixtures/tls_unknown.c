@@ -0,0 +1,18 @@
+#include "shelf.h"
+
+/* Asks __tls_get_addr for a module past the SHELF's own, which has to kill it */
+struct tls_index {
+    unsigned long module;
+    unsigned long offset;
+};
+
+extern void *__tls_get_addr(struct tls_index *index);
+
+__thread int tdata = 1;
+
+void _start(void)
+{
+    struct tls_index index = {2, 0};
+    __tls_get_addr(&index);
+    sys_exit(tdata);
+}
@@ -0,0 +1,696 @@
+//! Build tiny SHELFs from tests/fixtures with cc and run them through the loader
+//!
+//! The fixtures don't link against a libc so they make their own syscalls, which ties them to
+//! x86-64.
+#![cfg(target_arch = "x86_64")]
+
+use goblin::elf::header::{EM_AARCH64, EM_X86_64, ET_EXEC, ET_REL};
+use shelf_loader_poc::{DecryptScheme, Loader, LoaderError};
+use std::fs::File;
+use std::io::Write;
+use std::os::fd::FromRawFd;
+use std::os::unix::process::ExitStatusExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitStatus};
+
+// Compile tests/fixtures/<name>.c into a nolibc static-pie, plus any extra cc flags.
+// Each build gets its own output name so tests running in parallel don't share one.
+fn build_fixture(name: &str, output: &str, extra: &[&str]) -> PathBuf {
+    let mut flags = vec!["-nostdlib", "-static-pie", "-fPIE", "-O1"];
+    flags.extend(extra);
+    compile(name, output, &flags)
+}
+
+// Compile tests/fixtures/<name>.c with just the given cc flags
+fn compile(name: &str, output: &str, flags: &[&str]) -> PathBuf {
+    let fixtures = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures");
+    let out = Path::new(env!("CARGO_TARGET_TMPDIR")).join(output);
+    let status = Command::new("cc")
+        .args(flags)
+        .arg("-o")
+        .arg(&out)
+        .arg(fixtures.join(format!("{}.c", name)))
+        .status()
+        .expect("failed to run cc");
+    assert!(status.success(), "building {} failed", name);
+    out
+}
+
+// Run the loader on a SHELF as a child process and wait for it
+fn run(shelf: &Path, args: &[&str]) -> ExitStatus {
+    Command::new(env!("CARGO_BIN_EXE_shelf-loader-poc"))
+        .arg(shelf)
+        .args(args)
+        .status()
+        .expect("failed to run the loader")
+}
+
+// The permissions column of the /proc/self/maps line for the mapping holding addr, if any
+fn perms_at(addr: usize) -> Option<String> {
+    let maps = std::fs::read_to_string("/proc/self/maps").unwrap();
+    maps.lines().find_map(|line| {
+        let (range, rest) = line.split_once(' ')?;
+        let (start, end) = range.split_once('-')?;
+        let start = usize::from_str_radix(start, 16).ok()?;
+        let end = usize::from_str_radix(end, 16).ok()?;
+        (start..end).contains(&addr).then(|| rest[..4].to_string())
+    })
+}
+
+#[test]
+fn exits_with_code() {
+    let shelf = build_fixture("exit42", "exit42", &[]);
+    assert_eq!(run(&shelf, &[]).code(), Some(42));
+}
+
+#[test]
+fn single_rwx_segment() {
+    let shelf = build_fixture("exit42", "exit42-n", &["-Wl,-N"]);
+    assert_eq!(run(&shelf, &[]).code(), Some(42));
+}
+
+#[test]
+fn zeroes_bss() {
+    let shelf = build_fixture("bss", "bss", &[]);
+    assert_eq!(run(&shelf, &[]).code(), Some(42));
+}
+
+#[test]
+fn sets_up_tls() {
+    let shelf = build_fixture("tls", "tls", &[]);
+    assert_eq!(run(&shelf, &[]).code(), Some(42));
+}
+
+#[test]
+fn resolves_dynamic_tls() {
+    let flags = [
+        "-shared",
+        "-fPIC",
+        "-ftls-model=global-dynamic",
+        "-Wl,-e,_start",
+    ];
+    let shelf = build_fixture("tls_dynamic", "tls_dynamic", &flags);
+    assert_eq!(run(&shelf, &[]).code(), Some(42));
+}
+
+#[test]
+fn aborts_on_unknown_tls_modules() {
+    let flags = ["-shared", "-fPIC", "-Wl,-e,_start"];
+    let shelf = build_fixture("tls_unknown", "tls_unknown", &flags);
+    let output = Command::new(env!("CARGO_BIN_EXE_shelf-loader-poc"))
+        .arg(shelf)
+        .output()
+        .unwrap();
+    assert_eq!(output.status.signal(), Some(libc::SIGILL));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("unknown TLS module"), "{}", stderr);
+}
+
+#[test]
+fn runs_constructors_in_order() {
+    let shelf = build_fixture("init", "init", &[]);
+    let raw_file = std::fs::read(shelf).unwrap();
+    let status = Loader::from_bytes(&raw_file)
+        .run_init(true)
+        .spawn()
+        .unwrap();
+    assert_eq!(status.code(), Some(42));
+    // Left to the SHELF by default, which this one doesn't do
+    let status = Loader::from_bytes(&raw_file).spawn().unwrap();
+    assert_eq!(status.code(), Some(0));
+}
+
+#[test]
+fn applies_relocations() {
+    let shelf = build_fixture("reloc", "reloc", &[]);
+    assert_eq!(run(&shelf, &[]).code(), Some(42));
+}
+
+#[test]
+fn applies_packed_relocations() {
+    let shelf = build_fixture("reloc", "reloc-relr", &["-Wl,-z,pack-relative-relocs"]);
+    let status = Command::new(env!("CARGO_BIN_EXE_shelf-loader-poc"))
+        .arg("--relr")
+        .arg(&shelf)
+        .status()
+        .expect("failed to run the loader");
+    assert_eq!(status.code(), Some(42));
+}
+
+#[test]
+fn resolves_ifuncs() {
+    let shelf = build_fixture("ifunc", "ifunc", &[]);
+    assert_eq!(run(&shelf, &[]).code(), Some(42));
+}
+
+#[test]
+fn resolves_ifuncs_only_when_run() {
+    let shelf = build_fixture("ifunc_exit", "ifunc_exit", &[]);
+    let status = Command::new(env!("CARGO_BIN_EXE_shelf-loader-poc"))
+        .arg("--dump")
+        .arg(&shelf)
+        .stdout(std::process::Stdio::null())
+        .status()
+        .expect("failed to run the loader");
+    assert_eq!(status.code(), Some(0));
+    assert_eq!(run(&shelf, &[]).code(), Some(77));
+}
+
+#[test]
+fn applies_text_relocations() {
+    let shelf = build_fixture("textrel", "textrel", &["-Wl,-z,notext"]);
+    assert_eq!(run(&shelf, &[]).code(), Some(42));
+}
+
+#[test]
+fn passes_arguments() {
+    let shelf = build_fixture("argc", "argc", &[]);
+    // The SHELF's path becomes its argv[0]
+    assert_eq!(run(&shelf, &[]).code(), Some(1));
+    assert_eq!(run(&shelf, &["a"]).code(), Some(2));
+    assert_eq!(run(&shelf, &["a", "b"]).code(), Some(3));
+}
+
+#[test]
+fn aligns_the_stack() {
+    let shelf = build_fixture("align", "align", &[]);
+    let raw_file = std::fs::read(shelf).unwrap();
+    for reuse_stack in [false, true] {
+        for args in [vec!["align"], vec!["align", "a"]] {
+            let status = Loader::from_bytes(&raw_file)
+                .args(args.iter().map(|arg| arg.to_string()).collect())
+                .reuse_stack(reuse_stack)
+                .spawn()
+                .unwrap();
+            assert_eq!(status.code(), Some(42), "{:?} {}", args, reuse_stack);
+        }
+    }
+}
+
+#[test]
+fn maps_non_pie_at_its_address() {
+    let shelf = build_fixture("exit42", "exit42-exec", &["-no-pie", "-fno-pie", "-static"]);
+    assert_eq!(run(&shelf, &[]).code(), Some(42));
+}
+
+#[test]
+fn hands_off_to_the_dynamic_linker() {
+    let shelf = compile("hello", "hello", &["-O1"]);
+    let output = Command::new(env!("CARGO_BIN_EXE_shelf-loader-poc"))
+        .arg(&shelf)
+        .arg("a")
+        .output()
+        .expect("failed to run the loader");
+    assert_eq!(output.status.code(), Some(42));
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert_eq!(stdout, format!("hello from {}\n", shelf.display()));
+}
+
+#[test]
+fn dumps_layout() {
+    let shelf = build_fixture("exit42", "exit42-dump", &["-no-pie", "-fno-pie", "-static"]);
+    let output = Command::new(env!("CARGO_BIN_EXE_shelf-loader-poc"))
+        .arg("--dump")
+        .arg(&shelf)
+        .output()
+        .expect("failed to run the loader");
+    assert!(output.status.success());
+    let dump = String::from_utf8(output.stdout).unwrap();
+    // Non-PIE, so it's mapped at the address it was linked at
+    assert!(dump.starts_with("00400000-"), "{}", dump);
+    let entry = dump
+        .lines()
+        .find_map(|line| line.strip_prefix("entry 0x"))
+        .map(|entry| usize::from_str_radix(entry, 16).unwrap())
+        .unwrap();
+    let in_text = dump.lines().any(|line| {
+        let fields: Vec<&str> = line.split(' ').collect();
+        let Some((start, end)) = fields[0].split_once('-') else {
+            return false;
+        };
+        let start = usize::from_str_radix(start, 16).unwrap();
+        let end = usize::from_str_radix(end, 16).unwrap();
+        fields[1] == "r-xp" && fields[3] == "LOAD" && (start..end).contains(&entry)
+    });
+    assert!(in_text, "{}", dump);
+    assert!(dump.contains("\nAT_ENTRY "), "{}", dump);
+}
+
+#[test]
+fn rejects_missing_file() {
+    let status = run(Path::new("/nonexistent"), &[]);
+    assert_eq!(status.code(), Some(1));
+}
+
+#[test]
+fn spawn_reports_exit_status() {
+    let shelf = build_fixture("argc", "argc-spawn", &[]);
+    let raw_file = std::fs::read(shelf).unwrap();
+    let status = Loader::from_bytes(&raw_file)
+        .args(vec!["argc".into(), "a".into()])
+        .spawn()
+        .unwrap();
+    assert_eq!(status.code(), Some(2));
+}
+
+#[test]
+fn reuses_the_stack() {
+    let shelf = build_fixture("argc", "argc-reuse", &[]);
+    let raw_file = std::fs::read(shelf).unwrap();
+    let status = Loader::from_bytes(&raw_file)
+        .args(vec!["argc".into(), "a".into(), "b".into()])
+        .reuse_stack(true)
+        .spawn()
+        .unwrap();
+    assert_eq!(status.code(), Some(3));
+}
+
+#[test]
+fn passes_the_environment() {
+    let shelf = build_fixture("envc", "envc", &[]);
+    let raw_file = std::fs::read(shelf).unwrap();
+    let env = vec![("A".into(), "1".into()), ("B".into(), "2".into())];
+    for reuse_stack in [false, true] {
+        let loader = || Loader::from_bytes(&raw_file).reuse_stack(reuse_stack);
+        let status = loader().env(env.clone()).spawn().unwrap();
+        assert_eq!(status.code(), Some(2));
+        let status = loader().clear_env().spawn().unwrap();
+        assert_eq!(status.code(), Some(0));
+        let status = loader().spawn().unwrap();
+        assert_eq!(status.code(), Some(std::env::vars_os().count() as i32));
+    }
+}
+
+#[test]
+fn runs_exported_functions() {
+    let shelf = build_fixture("export", "export", &["-Wl,--export-dynamic"]);
+    let raw_file = std::fs::read(shelf).unwrap();
+    let image = Loader::from_bytes(&raw_file).map().unwrap();
+    assert!(image.symbol("nope").is_none());
+    let run = image.symbol("run").unwrap();
+    // exec_at doesn't return, so jump to run() in a child of our own
+    let pid = unsafe { libc::fork() };
+    assert!(pid >= 0);
+    if pid == 0 {
+        let _ = unsafe { Loader::from_bytes(&raw_file).exec_at(image, run) };
+        unsafe { libc::_exit(127) };
+    }
+    let mut status = 0;
+    assert_eq!(unsafe { libc::waitpid(pid, &mut status, 0) }, pid);
+    assert_eq!(ExitStatus::from_raw(status).code(), Some(33));
+}
+
+#[test]
+fn spawn_reports_panics() {
+    let shelf = build_fixture("exit42", "exit42-panic", &[]);
+    let raw_file = std::fs::read(shelf).unwrap();
+    let err = Loader::from_bytes(&raw_file)
+        .on_before_exec(|_| panic!("in the child"))
+        .spawn()
+        .unwrap_err();
+    // Only the parent gets here, the child exits with its message
+    match err {
+        LoaderError::ChildFailed(msg) => assert!(msg.contains("in the child"), "{}", msg),
+        err => panic!("{}", err),
+    }
+}
+
+#[test]
+fn edits_auxv_before_exec() {
+    let shelf = build_fixture("auxv", "auxv", &[]);
+    let raw_file = std::fs::read(shelf).unwrap();
+    let status = Loader::from_bytes(&raw_file)
+        .on_before_exec(|context| {
+            let flags = context
+                .auxv
+                .iter_mut()
+                .find(|aux| aux.key == libc::AT_FLAGS as usize)
+                .unwrap();
+            flags.value = 42;
+        })
+        .spawn()
+        .unwrap();
+    assert_eq!(status.code(), Some(42));
+}
+
+#[cfg(feature = "gzip")]
+#[test]
+fn runs_gzip_compressed() {
+    let shelf = build_fixture("exit42", "exit42-gzip", &[]);
+    let mut encoder = flate2::write::GzEncoder::new(vec![], flate2::Compression::default());
+    encoder.write_all(&std::fs::read(shelf).unwrap()).unwrap();
+    let compressed = encoder.finish().unwrap();
+    let status = Loader::from_vec(compressed).spawn().unwrap();
+    assert_eq!(status.code(), Some(42));
+}
+
+#[cfg(feature = "zstd")]
+#[test]
+fn runs_zstd_compressed() {
+    let shelf = build_fixture("exit42", "exit42-zstd", &[]);
+    let compressed = zstd::encode_all(&std::fs::read(shelf).unwrap()[..], 0).unwrap();
+    let status = Loader::from_vec(compressed).spawn().unwrap();
+    assert_eq!(status.code(), Some(42));
+}
+
+#[test]
+fn decrypts_xor() {
+    let shelf = build_fixture("exit42", "exit42-xor", &[]);
+    let key = b"shelf".to_vec();
+    let encrypted: Vec<u8> = std::fs::read(shelf)
+        .unwrap()
+        .iter()
+        .zip(key.iter().cycle())
+        .map(|(byte, key)| byte ^ key)
+        .collect();
+    assert!(Loader::from_bytes(&encrypted).map().is_err());
+    let image = Loader::from_bytes(&encrypted)
+        .decrypt_with(DecryptScheme::Xor(key))
+        .map()
+        .unwrap();
+    assert!(!image.segments.is_empty());
+}
+
+#[cfg(feature = "aes")]
+#[test]
+fn decrypts_aes_and_checks_the_tag() {
+    use aes_gcm::aead::{Aead, KeyInit};
+    use aes_gcm::{Aes256Gcm, Key, Nonce};
+
+    let shelf = build_fixture("exit42", "exit42-aes", &[]);
+    let (key, nonce) = ([7; 32], [9; 12]);
+    let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&key));
+    let mut encrypted = cipher
+        .encrypt(
+            Nonce::from_slice(&nonce),
+            &std::fs::read(shelf).unwrap()[..],
+        )
+        .unwrap();
+    let decrypt = |encrypted: &[u8], key| {
+        Loader::from_bytes(encrypted)
+            .decrypt_with(DecryptScheme::Aes256Gcm { key, nonce })
+            .map()
+    };
+    assert!(!decrypt(&encrypted, key).unwrap().segments.is_empty());
+    let err = decrypt(&encrypted, [8; 32]).unwrap_err();
+    assert!(matches!(err, LoaderError::DecryptFailed), "{}", err);
+    // The tag is the last 16 bytes
+    *encrypted.last_mut().unwrap() ^= 1;
+    let err = decrypt(&encrypted, key).unwrap_err();
+    assert!(matches!(err, LoaderError::DecryptFailed), "{}", err);
+}
+
+#[test]
+fn spawn_reports_load_errors() {
+    let err = Loader::from_bytes(b"not an elf").spawn().unwrap_err();
+    assert!(matches!(err, LoaderError::ChildFailed(_)));
+}
+
+#[test]
+fn rejects_overlapping_segments() {
+    let shelf = build_fixture("exit42", "exit42-overlap", &[]);
+    let raw_file = std::fs::read(shelf).unwrap();
+    let read_u64 = |at: usize| u64::from_le_bytes(raw_file[at..at + 8].try_into().unwrap());
+    let read_u16 = |at: usize| u16::from_le_bytes(raw_file[at..at + 2].try_into().unwrap());
+    let (phoff, phentsize, phnum) = (read_u64(32) as usize, read_u16(54), read_u16(56));
+    let loads: Vec<usize> = (0..phnum as usize)
+        .map(|i| phoff + i * phentsize as usize)
+        .filter(|&h| raw_file[h..h + 4] == 1u32.to_le_bytes())
+        .collect();
+    let (first, last) = (loads[0], loads[loads.len() - 1]);
+    let first_vaddr = read_u64(first + 16);
+
+    // Move the last segment on top of the first at a few different offsets into it
+    for offset in [0, 1, 0x10] {
+        let mut patched = raw_file.clone();
+        patched[last + 16..last + 24].copy_from_slice(&(first_vaddr + offset).to_le_bytes());
+        let err = Loader::from_bytes(&patched).map().unwrap_err();
+        assert!(
+            matches!(err, LoaderError::OverlappingSegments),
+            "offset {:#x}: {}",
+            offset,
+            err
+        );
+    }
+}
+
+// A minimal ELF64 with one loadable segment, in either byte order
+fn minimal_elf(big_endian: bool, e_type: u16, e_machine: u16) -> Vec<u8> {
+    let half = |v: u16| {
+        if big_endian {
+            v.to_be_bytes()
+        } else {
+            v.to_le_bytes()
+        }
+    };
+    let word = |v: u32| {
+        if big_endian {
+            v.to_be_bytes()
+        } else {
+            v.to_le_bytes()
+        }
+    };
+    let xword = |v: u64| {
+        if big_endian {
+            v.to_be_bytes()
+        } else {
+            v.to_le_bytes()
+        }
+    };
+    let data = if big_endian { 2 } else { 1 };
+    let mut elf = vec![
+        0x7f, b'E', b'L', b'F', 2, data, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+    ];
+    elf.extend(half(e_type));
+    elf.extend(half(e_machine));
+    elf.extend(word(1)); // e_version
+    elf.extend(xword(0x400000)); // e_entry
+    elf.extend(xword(64)); // e_phoff
+    elf.extend(xword(0)); // e_shoff
+    elf.extend(word(0)); // e_flags
+    for value in [64u16, 56, 1, 64, 0, 0] {
+        // e_ehsize, e_phentsize, e_phnum, e_shentsize, e_shnum, e_shstrndx
+        elf.extend(half(value));
+    }
+    elf.extend(word(1)); // p_type: PT_LOAD
+    elf.extend(word(5)); // p_flags: PF_R | PF_X
+    for value in [0u64, 0x400000, 0x400000, 120, 120, 0x1000] {
+        // p_offset, p_vaddr, p_paddr, p_filesz, p_memsz, p_align
+        elf.extend(xword(value));
+    }
+    elf
+}
+
+#[test]
+fn rejects_other_byte_order() {
+    let err = Loader::from_vec(minimal_elf(true, ET_EXEC, EM_X86_64))
+        .map()
+        .unwrap_err();
+    assert!(matches!(err, LoaderError::EndianMismatch), "{}", err);
+}
+
+#[test]
+fn aligns_the_base_to_p_align() {
+    let shelf = build_fixture("exit42", "exit42-align", &["-Wl,-z,max-page-size=0x200000"]);
+    let raw_file = std::fs::read(&shelf).unwrap();
+    for aslr in [false, true] {
+        let image = Loader::from_bytes(&raw_file).aslr(aslr).map().unwrap();
+        assert_eq!(image.base as usize % 0x200000, 0, "{:?}", image.base);
+    }
+    assert_eq!(run(&shelf, &[]).code(), Some(42));
+}
+
+#[cfg(target_arch = "x86_64")]
+#[test]
+fn randomizes_the_base() {
+    let shelf = build_fixture("exit42", "exit42-aslr", &[]);
+    let raw_file = std::fs::read(shelf).unwrap();
+    let first = Loader::from_bytes(&raw_file).aslr(true).map().unwrap();
+    let second = Loader::from_bytes(&raw_file).aslr(true).map().unwrap();
+    for image in [&first, &second] {
+        assert!(
+            (1 << 32..1 << 46).contains(&(image.base as usize)),
+            "{:?}",
+            image.base
+        );
+    }
+    assert_ne!(first.base, second.base);
+}
+
+#[test]
+fn leaves_gaps_inaccessible() {
+    let shelf = build_fixture("exit42", "exit42-gaps", &["-Wl,-z,max-page-size=0x10000"]);
+    let raw_file = std::fs::read(shelf).unwrap();
+    let image = Loader::from_bytes(&raw_file).map().unwrap();
+    // The page after the first segment is in a gap before the next one
+    let first = &image.segments[0];
+    let gap = (first.addr as usize + first.len + 0xfff) & !0xfff;
+    assert!(image.segments.iter().all(|segment| {
+        let start = segment.addr as usize;
+        gap + 0x1000 <= start || gap >= start + segment.len
+    }));
+    assert_eq!(perms_at(gap).unwrap(), "---p");
+}
+
+#[test]
+fn protects_relro() {
+    let shelf = build_fixture("reloc", "reloc-relro", &["-Wl,-z,relro,-z,now"]);
+    let raw_file = std::fs::read(shelf).unwrap();
+    for relro in [false, true] {
+        let image = Loader::from_bytes(&raw_file).relro(relro).map().unwrap();
+        let segment = image.relro.as_ref().unwrap();
+        // The RELRO range ends on a page boundary, so its last page is all RELRO
+        let last = segment.addr as usize + segment.len - 1;
+        let perms = if relro { "r--p" } else { "rw-p" };
+        assert_eq!(perms_at(last).unwrap(), perms);
+    }
+}
+
+#[test]
+fn unmaps_dropped_images() {
+    let shelf = build_fixture("tls", "tls-drop", &[]);
+    let raw_file = std::fs::read(shelf).unwrap();
+    // A random base keeps other tests' mappings from landing there once it's free
+    let image = Loader::from_bytes(&raw_file).aslr(true).map().unwrap();
+    let base = image.base as usize;
+    assert!(perms_at(base).is_some());
+    drop(image);
+    assert_eq!(perms_at(base), None);
+}
+
+#[test]
+fn rejects_relocations_into_gaps() {
+    let shelf = build_fixture("reloc", "reloc-gaps", &["-Wl,-z,max-page-size=0x10000"]);
+    let mut raw_file = std::fs::read(shelf).unwrap();
+    let elf = goblin::elf::Elf::parse(&raw_file).unwrap();
+    let rela = elf
+        .section_headers
+        .iter()
+        .find(|sh| elf.shdr_strtab.get_at(sh.sh_name) == Some(".rela.dyn"))
+        .unwrap()
+        .sh_offset as usize;
+    // The first segment ends well before 0x1000 and the next starts at 0x10000
+    raw_file[rela..rela + 8].copy_from_slice(&0x1000u64.to_le_bytes());
+    let err = Loader::from_vec(raw_file).map().unwrap_err();
+    assert!(matches!(err, LoaderError::BadOffset), "{}", err);
+}
+
+#[test]
+fn loads_from_memfd() {
+    let shelf = build_fixture("exit42", "exit42-memfd", &[]);
+    let raw_file = std::fs::read(shelf).unwrap();
+    let fd = unsafe { libc::memfd_create(c"shelf".as_ptr(), libc::MFD_ALLOW_SEALING) };
+    assert!(fd >= 0);
+    let mut memfd = unsafe { File::from_raw_fd(fd) };
+    memfd.write_all(&raw_file).unwrap();
+    let seals = libc::F_SEAL_SEAL | libc::F_SEAL_SHRINK | libc::F_SEAL_GROW | libc::F_SEAL_WRITE;
+    assert_eq!(unsafe { libc::fcntl(fd, libc::F_ADD_SEALS, seals) }, 0);
+    // Read in full even though the offset is at the end after writing
+    let status = Loader::from_fd(&memfd).unwrap().spawn().unwrap();
+    assert_eq!(status.code(), Some(42));
+}
+
+#[test]
+fn loads_from_pipe() {
+    let shelf = build_fixture("exit42", "exit42-pipe", &[]);
+    let raw_file = std::fs::read(shelf).unwrap();
+    let (reader, mut writer) = std::io::pipe().unwrap();
+    // Written alongside in case it outgrows the pipe buffer, and read until the writer is dropped
+    let writer = std::thread::spawn(move || writer.write_all(&raw_file).unwrap());
+    let loader = Loader::from_fd(&reader).unwrap();
+    writer.join().unwrap();
+    assert_eq!(loader.spawn().unwrap().code(), Some(42));
+}
+
+#[test]
+fn rejects_garbage() {
+    let err = Loader::from_bytes(b"0123456789").map().unwrap_err();
+    assert!(matches!(err, LoaderError::Parse(_)), "{}", err);
+}
+
+#[test]
+fn rejects_object_files() {
+    let err = Loader::from_vec(minimal_elf(false, ET_REL, EM_X86_64))
+        .map()
+        .unwrap_err();
+    assert!(
+        matches!(err, LoaderError::UnsupportedElfType(ET_REL)),
+        "{}",
+        err
+    );
+}
+
+#[test]
+fn rejects_other_architectures() {
+    let err = Loader::from_vec(minimal_elf(false, ET_EXEC, EM_AARCH64))
+        .map()
+        .unwrap_err();
+    assert!(
+        matches!(
+            err,
+            LoaderError::ArchMismatch {
+                expected: EM_X86_64,
+                found: EM_AARCH64
+            }
+        ),
+        "{}",
+        err
+    );
+}
+
+#[test]
+fn reserves_only_the_segments_span() {
+    // 1 GiB up, and above where the kernel maps things so vaddr 0 would be below address 0
+    for vaddr in [0x40000000usize, 0x7ff000000000] {
+        let ttext = format!("-Wl,-Ttext-segment={:#x}", vaddr);
+        let output = format!("exit42-{:#x}", vaddr);
+        let shelf = build_fixture("exit42", &output, &[&ttext]);
+        let mut raw_file = std::fs::read(shelf).unwrap();
+        // ld makes it ET_EXEC, but the code is position independent so it can go anywhere as
+        // ET_DYN
+        raw_file[16..18].copy_from_slice(&3u16.to_le_bytes());
+        let image = Loader::from_vec(raw_file).map().unwrap();
+        let start = image
+            .segments
+            .iter()
+            .map(|s| s.addr as usize)
+            .min()
+            .unwrap();
+        let end = image
+            .segments
+            .iter()
+            .map(|s| s.addr as usize + s.len)
+            .max()
+            .unwrap();
+        assert_eq!(start.wrapping_sub(image.base as usize), vaddr);
+        assert!(end - start < 0x10000, "{:#x}", end - start);
+        if (image.base as usize) > start {
+            continue;
+        }
+        // Reserving from vaddr 0 would leave everything below the first segment mapped
+        let maps = std::fs::read_to_string("/proc/self/maps").unwrap();
+        let below = maps.lines().find_map(|line| {
+            let (range, _) = line.split_once(' ')?;
+            let (from, to) = range.split_once('-')?;
+            let from = usize::from_str_radix(from, 16).ok()?;
+            let to = usize::from_str_radix(to, 16).ok()?;
+            (from..to).contains(&(start - 1)).then_some(from)
+        });
+        assert!(
+            below.is_none_or(|from| from > image.base as usize),
+            "{}",
+            maps
+        );
+    }
+}
+
+#[test]
+fn rejects_segments_at_the_end_of_memory() {
+    let mut elf = minimal_elf(false, ET_EXEC, EM_X86_64);
+    // Fits below 2^64, but not once it's rounded up to a whole page
+    let vaddr = 0xffff_ffff_ffff_f000u64.to_le_bytes();
+    elf[24..32].copy_from_slice(&vaddr); // e_entry
+    elf[80..88].copy_from_slice(&vaddr); // p_vaddr
+    let err = Loader::from_vec(elf).map().unwrap_err();
+    assert!(matches!(err, LoaderError::BadOffset), "{}", err);
+}